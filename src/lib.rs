@@ -0,0 +1,3948 @@
+//! The synth engine: voices, filters, tracks and the step sequencer.
+//! Nothing here touches an audio device, so it runs offline as well as
+//! live (renders, tests).
+
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+
+//
+// =========================
+//   V O I C E
+// =========================
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Waveform { Sine, Saw, Square, Triangle, Noise, PinkNoise }
+
+impl Waveform {
+    /// One cycle of the shape at `phase` in 0..1, output in -1..1. Noise
+    /// has no cycle; voices generate it themselves, so it's silent here.
+    pub fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Saw => 2.0 * (phase - 0.5),
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - (4.0 * (phase - 0.25)).abs(),
+            Waveform::Noise | Waveform::PinkNoise => 0.0,
+        }
+    }
+
+    pub fn is_noise(self) -> bool { matches!(self, Waveform::Noise | Waveform::PinkNoise) }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "sine" => Some(Waveform::Sine),
+            "saw" => Some(Waveform::Saw),
+            "square" => Some(Waveform::Square),
+            "triangle" | "tri" => Some(Waveform::Triangle),
+            "noise" | "white" => Some(Waveform::Noise),
+            "pink" | "pinknoise" => Some(Waveform::PinkNoise),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Voice {
+    phase: f32,
+    frequency: f32,
+    // portamento: `frequency` slides towards this over `glide` seconds
+    target_frequency: f32,
+    glide: f32,
+    waveform: Waveform,
+    amp: f32,
+    /// Per-note level multiplier set at trigger time (accents)
+    velocity: f32,
+    /// Envelope peak and attack-time multiplier for this note (see `set_velocity`)
+    env_peak: f32,
+    attack_scale: f32,
+    // simple ADSR: times in seconds (anything below MIN_ENV_TIME is
+    // treated as MIN_ENV_TIME), sustain is a level in 0..=1
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    env_phase: f32,
+    // level the attack starts from, so a retrigger mid-note doesn't click
+    attack_from: f32,
+    // last envelope value, latched by note_off
+    env_level: f32,
+    released: bool,
+    release_from: f32,
+    release_phase: f32,
+    // 2-operator FM: a sine modulator at frequency * fm_ratio shifts the
+    // carrier's phase by up to fm_index radians (0 = plain oscillator)
+    fm_ratio: f32,
+    fm_index: f32,
+    mod_phase: f32,
+    // panic fade-out, cleared by the next trigger
+    killing: bool,
+    kill_gain: f32,
+    // slow pitch drift in cents, driven by the sequencer's random walk
+    drift_walk: f32,
+    drift_cents: f32,
+    // continuous pitch offset in semitones (LFO vibrato), set per sample
+    pub pitch_mod: f32,
+    // fixed unison detune and this voice's place in the stereo spread
+    // (-1 = left .. 1 = right), set at trigger time
+    pub detune_cents: f32,
+    pub unison_pos: f32,
+    // noise source, seeded differently for every voice, and the pink
+    // filter state
+    noise: Rng,
+    pink: [f32; 3],
+    // last raw oscillator sample, so a noise voice knows where it was
+    last_sample: f32,
+    // declick: a jump in the raw waveform (switching shape, resetting
+    // phase) is bridged by this offset, fading out over `declick_time`
+    pub declick_time: f32,
+    declick_offset: f32,
+    declick_pos: f32,
+    // bounds for the sounding pitch, after detune, drift and pitch LFO
+    pub freq_min: f32,
+    pub freq_max: f32,
+}
+
+// hands each new voice its own noise seed
+static NEXT_NOISE_SEED: AtomicU64 = AtomicU64::new(1);
+
+// panic fade length: short enough to feel instant, long enough not to click
+const PANIC_FADE: f32 = 0.005;
+
+// envelope a voice (and a track without its own) starts with
+const DEFAULT_ATTACK: f32 = 0.01;
+const DEFAULT_DECAY: f32 = 0.1;
+const DEFAULT_SUSTAIN: f32 = 0.3;
+const DEFAULT_RELEASE: f32 = 0.1;
+
+// shortest envelope segment; keeps the segment divisions finite
+const MIN_ENV_TIME: f32 = 1e-4;
+
+// long enough to hide a waveform jump, short enough not to hear as a fade
+pub const DEFAULT_DECLICK: f32 = 0.003;
+pub const MAX_DECLICK: f32 = 0.02;
+
+// a glide this close to its target just lands
+const GLIDE_SNAP_HZ: f32 = 0.01;
+pub const MAX_GLIDE: f32 = 2.0;
+
+/// Fresh voices for one track (built one by one so each gets its own
+/// noise seed; cloning would share it).
+fn voice_pool(n: usize) -> Vec<Voice> {
+    (0..n).map(|_| Voice::new()).collect()
+}
+
+impl Default for Voice {
+    fn default() -> Self { Self::new() }
+}
+
+impl Voice {
+    pub fn new() -> Self {
+        Self {
+            phase: 0.0,
+            frequency: 440.0,
+            target_frequency: 440.0,
+            glide: 0.0,
+            waveform: Waveform::Saw,
+            amp: 0.15,
+            velocity: 1.0,
+            env_peak: 1.0,
+            attack_scale: 1.0,
+            attack: DEFAULT_ATTACK,
+            decay: DEFAULT_DECAY,
+            sustain: DEFAULT_SUSTAIN,
+            release: DEFAULT_RELEASE,
+            env_phase: 0.0,
+            attack_from: 0.0,
+            env_level: 0.0,
+            // silent until the first trigger
+            released: true,
+            release_from: 0.0,
+            release_phase: f32::MAX,
+            fm_ratio: 1.0,
+            fm_index: 0.0,
+            mod_phase: 0.0,
+            killing: false,
+            kill_gain: 1.0,
+            drift_walk: 0.0,
+            drift_cents: 0.0,
+            pitch_mod: 0.0,
+            detune_cents: 0.0,
+            unison_pos: 0.0,
+            noise: Rng::new(NEXT_NOISE_SEED.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9E37_79B9_7F4A_7C15)),
+            pink: [0.0; 3],
+            last_sample: 0.0,
+            declick_time: DEFAULT_DECLICK,
+            declick_offset: 0.0,
+            freq_min: 0.0,
+            freq_max: f32::MAX,
+            declick_pos: 0.0,
+        }
+    }
+
+    /// Pink (-3 dB/octave) noise from white: Paul Kellet's economy filter.
+    fn pink_noise(&mut self) -> f32 {
+        let white = self.noise.bipolar();
+        let b = &mut self.pink;
+        b[0] = 0.99765 * b[0] + white * 0.0990460;
+        b[1] = 0.96300 * b[1] + white * 0.2965164;
+        b[2] = 0.57000 * b[2] + white * 1.0526913;
+        // roughly back to -1..1
+        (b[0] + b[1] + b[2] + white * 0.1848) * 0.25
+    }
+
+    pub fn seed_noise(&mut self, seed: u64) { self.noise = Rng::new(seed); }
+
+    pub fn set_frequency(&mut self, freq: f32) {
+        self.frequency = freq;
+        self.target_frequency = freq;
+    }
+
+    /// Slide to `freq` over roughly `glide` seconds instead of jumping.
+    pub fn glide_to(&mut self, freq: f32, glide: f32) {
+        self.target_frequency = freq;
+        self.glide = glide;
+    }
+
+    pub fn reset_phase(&mut self) {
+        let before = self.wave_now();
+        self.phase = 0.0;
+        self.mod_phase = 0.0;
+        self.declick(before);
+    }
+
+    /// Change the oscillator shape; a sounding note crossfades rather
+    /// than jumping.
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        if waveform == self.waveform { return; }
+        let before = self.wave_now();
+        self.waveform = waveform;
+        self.declick(before);
+    }
+
+    /// Oscillator phase after FM.
+    fn osc_phase(&self) -> f32 {
+        if self.fm_index != 0.0 {
+            let modulator = (2.0 * PI * self.mod_phase).sin();
+            (self.phase + self.fm_index * modulator / (2.0 * PI)).rem_euclid(1.0)
+        } else {
+            self.phase
+        }
+    }
+
+    /// Raw oscillator value right now; noise has no phase, so its last sample.
+    fn wave_now(&self) -> f32 {
+        if self.waveform.is_noise() { self.last_sample } else { self.waveform.sample(self.osc_phase()) }
+    }
+
+    /// Bridge the jump from `before` to the waveform's new value, on top
+    /// of whatever is left of a previous one. Silent voices don't need it,
+    /// nor do frequency changes: the phase carries on, so the wave never
+    /// jumps.
+    fn declick(&mut self, before: f32) {
+        if self.declick_time <= 0.0 || self.is_finished() { return; }
+        self.declick_offset = self.declick_remaining() + before - self.wave_now();
+        self.declick_pos = 0.0;
+    }
+
+    fn declick_remaining(&self) -> f32 {
+        if self.declick_time <= 0.0 { return 0.0; }
+        self.declick_offset * (1.0 - self.declick_pos / self.declick_time).max(0.0)
+    }
+
+    /// Set the note's velocity. `vel_to_env` (0..1) lets it also shape the
+    /// envelope: harder hits peak higher and attack faster, softer ones
+    /// the opposite. At 0 velocity only scales the output level.
+    pub fn set_velocity(&mut self, velocity: f32, vel_to_env: f32) {
+        self.velocity = velocity;
+        let shape = (1.0 + vel_to_env.clamp(0.0, 1.0) * (velocity - 1.0)).max(0.1);
+        self.env_peak = shape;
+        self.attack_scale = (1.0 / shape).clamp(0.25, 4.0);
+    }
+
+    pub fn set_adsr(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) {
+        self.attack = attack;
+        self.decay = decay;
+        self.sustain = sustain;
+        self.release = release;
+    }
+
+    pub fn set_fm(&mut self, ratio: f32, index: f32) {
+        self.fm_ratio = ratio;
+        self.fm_index = index;
+    }
+
+    pub fn process(&mut self, sample_rate: f32) -> f32 {
+        let phase = self.osc_phase();
+        let mut sample = match self.waveform {
+            Waveform::Noise => self.noise.bipolar(),
+            Waveform::PinkNoise => self.pink_noise(),
+            wave => wave.sample(phase),
+        };
+        self.last_sample = sample;
+        if self.declick_offset != 0.0 {
+            sample += self.declick_remaining();
+            self.declick_pos += 1.0 / sample_rate;
+            if self.declick_pos >= self.declick_time { self.declick_offset = 0.0; }
+        }
+
+        if self.frequency != self.target_frequency {
+            let coeff = 1.0 - (-1.0 / (self.glide.max(MIN_ENV_TIME) * sample_rate)).exp();
+            self.frequency += (self.target_frequency - self.frequency) * coeff;
+            if (self.target_frequency - self.frequency).abs() < GLIDE_SNAP_HZ {
+                self.frequency = self.target_frequency;
+            }
+        }
+
+        let cents = self.drift_cents + self.detune_cents + self.pitch_mod * 100.0;
+        let freq = if cents != 0.0 {
+            self.frequency * 2f32.powf(cents / 1200.0)
+        } else {
+            self.frequency
+        }.clamp(self.freq_min, self.freq_max);
+        self.phase += freq / sample_rate;
+        if self.phase >= 1.0 { self.phase -= 1.0; }
+        if self.fm_index != 0.0 {
+            self.mod_phase = (self.mod_phase + freq * self.fm_ratio / sample_rate).fract();
+        }
+
+        // simple envelope
+        let attack = (self.attack * self.attack_scale).max(MIN_ENV_TIME);
+        let decay = self.decay.max(MIN_ENV_TIME);
+        let sustain = self.sustain.clamp(0.0, 1.0);
+        let peak = self.env_peak;
+        let env = if self.released {
+            let release = self.release.max(MIN_ENV_TIME);
+            let env = self.release_from * (1.0 - self.release_phase / release).max(0.0);
+            self.release_phase += 1.0 / sample_rate;
+            env
+        } else if self.env_phase < attack {
+            self.attack_from + (peak - self.attack_from) * self.env_phase / attack
+        } else if self.env_phase < attack + decay {
+            peak - ((self.env_phase - attack)/decay)*(peak - sustain)
+        } else {
+            sustain
+        };
+        self.env_level = env;
+
+        self.env_phase += 1.0 / sample_rate;
+
+        if self.killing {
+            self.kill_gain = (self.kill_gain - 1.0 / (PANIC_FADE * sample_rate)).max(0.0);
+        }
+
+        sample * self.amp * self.velocity * env * self.kill_gain
+    }
+
+    /// Retrigger the envelope. The attack rises from wherever the envelope
+    /// currently is, so retriggering during a note or its release is smooth.
+    pub fn reset_env(&mut self) {
+        self.attack_from = self.env_level;
+        self.env_phase = 0.0;
+        self.released = false;
+        self.killing = false;
+        self.kill_gain = 1.0;
+    }
+
+    /// Start the release: ramp from the current level to 0 over `release`.
+    pub fn note_off(&mut self) {
+        if self.released { return; }
+        self.released = true;
+        self.release_from = self.env_level;
+        self.release_phase = 0.0;
+    }
+
+    /// True while a note is sounding and hasn't been released or killed.
+    pub fn is_held(&self) -> bool { !self.released && !self.killing }
+
+    /// True once the release has run its course (or before the first note).
+    pub fn is_finished(&self) -> bool {
+        self.released && self.release_phase >= self.release.max(MIN_ENV_TIME)
+    }
+
+    /// Fade to silence over PANIC_FADE; the next trigger brings it back.
+    pub fn kill(&mut self) { self.killing = true; }
+
+    pub fn is_silent(&self) -> bool { self.kill_gain == 0.0 }
+}
+
+//
+// =========================
+//   F I L T E R
+// =========================
+//
+
+/// RBJ-cookbook biquad (direct form I).
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b0: f32, b1: f32, b2: f32,
+    a1: f32, a2: f32,
+    x1: f32, x2: f32,
+    y1: f32, y2: f32,
+}
+
+impl Default for Biquad {
+    fn default() -> Self { Self::new() }
+}
+
+impl Biquad {
+    /// Pass-through until coefficients are set
+    pub fn new() -> Self {
+        Self { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0, x1: 0.0, x2: 0.0, y1: 0.0, y2: 0.0 }
+    }
+
+    /// Low-pass coefficients. Cutoff is clamped below Nyquist and q kept
+    /// positive so the filter stays stable.
+    pub fn set_lowpass(&mut self, cutoff: f32, q: f32, sample_rate: f32) {
+        let cutoff = cutoff.clamp(10.0, sample_rate * 0.45);
+        let q = q.max(0.1);
+        let w0 = 2.0 * PI * cutoff / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        self.b0 = (1.0 - cos) / 2.0 / a0;
+        self.b1 = (1.0 - cos) / a0;
+        self.b2 = self.b0;
+        self.a1 = -2.0 * cos / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Resonant low-pass for a track. Coefficients are recomputed only when
+/// cutoff or resonance actually change.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub cutoff: f32,
+    /// 0 = flat Butterworth, 1 = strongly resonant
+    pub resonance: f32,
+    biquad: Biquad,
+    sample_rate: f32,
+}
+
+// cutoffs at or above this leave the signal untouched
+const FILTER_OPEN: f32 = 20000.0;
+
+impl Filter {
+    pub fn new(sample_rate: f32) -> Self {
+        Self { cutoff: FILTER_OPEN, resonance: 0.0, biquad: Biquad::new(), sample_rate }
+    }
+
+    pub fn set(&mut self, cutoff: f32, resonance: f32) {
+        if cutoff == self.cutoff && resonance == self.resonance { return; }
+        self.cutoff = cutoff;
+        self.resonance = resonance;
+        let q = std::f32::consts::FRAC_1_SQRT_2 + resonance.clamp(0.0, 1.0) * 9.3;
+        self.biquad.set_lowpass(cutoff, q, self.sample_rate);
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        if self.cutoff >= FILTER_OPEN { return input; }
+        self.biquad.process(input)
+    }
+}
+
+/// Gate driven by a peak follower: open while the input is above the
+/// threshold, fading closed over the release time once it drops below.
+#[derive(Clone, Debug, Default)]
+pub struct NoiseGate {
+    env: f32,
+    gain: f32,
+}
+
+// follower decay and gate opening time, in seconds
+const GATE_HOLD: f32 = 0.01;
+const GATE_ATTACK: f32 = 0.001;
+
+impl NoiseGate {
+    pub fn process(&mut self, x: f32, threshold_db: f32, release: f32, sample_rate: f32) -> f32 {
+        let follow = (-1.0 / (GATE_HOLD * sample_rate)).exp();
+        self.env = x.abs().max(self.env * follow);
+
+        let open = to_db(self.env) >= threshold_db;
+        let (target, time) = if open { (1.0, GATE_ATTACK) } else { (0.0, release.max(MIN_ENV_TIME)) };
+        let coeff = 1.0 - (-1.0 / (time * sample_rate)).exp();
+        self.gain += (target - self.gain) * coeff;
+        x * self.gain
+    }
+}
+
+/// Feedback echo on a ring buffer. The buffer holds `DELAY_MAX_SECS`, so
+/// changing the time never reallocates on the audio thread.
+#[derive(Clone, Debug)]
+pub struct Delay {
+    buffer: Vec<f32>,
+    write_pos: usize,
+    sample_rate: f32,
+    pub time_secs: f32,
+    pub feedback: f32,
+    /// Wet level, 0 = dry only (delay off)
+    pub mix: f32,
+}
+
+// longest echo time, and the feedback ceiling that keeps repeats decaying
+pub const DELAY_MAX_SECS: f32 = 2.0;
+pub const DELAY_MAX_FEEDBACK: f32 = 0.95;
+
+impl Delay {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            buffer: vec![0.0; (sample_rate * DELAY_MAX_SECS) as usize + 1],
+            write_pos: 0,
+            sample_rate,
+            time_secs: 0.0,
+            feedback: 0.0,
+            mix: 0.0,
+        }
+    }
+
+    pub fn set(&mut self, time_secs: f32, feedback: f32, mix: f32) {
+        self.time_secs = time_secs.clamp(0.0, DELAY_MAX_SECS);
+        self.feedback = feedback.clamp(0.0, DELAY_MAX_FEEDBACK);
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let cap = self.buffer.len();
+        let lag = ((self.time_secs * self.sample_rate) as usize).clamp(1, cap - 1);
+        let echo = self.buffer[(self.write_pos + cap - lag) % cap];
+        self.buffer[self.write_pos] = input + echo * self.feedback;
+        self.write_pos = (self.write_pos + 1) % cap;
+        input + echo * self.mix
+    }
+}
+
+/// Lo-fi insert: sample-and-hold for `downsample` samples, then amplitude
+/// quantized to `2^bits` levels across -1..1. 16 bits with no downsampling
+/// is effectively transparent.
+#[derive(Clone, Debug)]
+pub struct BitCrush {
+    pub bits: u8,
+    pub downsample: usize,
+    held: f32,
+    counter: usize,
+}
+
+// crush settings that leave the signal alone, and the coarsest hold
+pub const CRUSH_BITS_OFF: u8 = 16;
+pub const MAX_CRUSH_DOWNSAMPLE: usize = 64;
+
+impl Default for BitCrush {
+    fn default() -> Self {
+        Self { bits: CRUSH_BITS_OFF, downsample: 1, held: 0.0, counter: 0 }
+    }
+}
+
+impl BitCrush {
+    pub fn set(&mut self, bits: u8, downsample: usize) {
+        self.bits = bits.clamp(1, CRUSH_BITS_OFF);
+        self.downsample = downsample.clamp(1, MAX_CRUSH_DOWNSAMPLE);
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        if self.counter == 0 {
+            let steps = ((1u32 << self.bits) - 1) as f32;
+            let level = ((input.clamp(-1.0, 1.0) + 1.0) * 0.5 * steps).round();
+            self.held = level / steps * 2.0 - 1.0;
+        }
+        self.counter = (self.counter + 1) % self.downsample.max(1);
+        self.held
+    }
+}
+
+/// Feedback comb with a one-pole low-pass in the loop (Freeverb's
+/// building block); the low-pass is what makes the tail darken.
+#[derive(Clone, Debug)]
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self { Self { buffer: vec![0.0; len.max(1)], pos: 0, store: 0.0 } }
+
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let out = self.buffer[self.pos];
+        self.store = out * (1.0 - damp) + self.store * damp;
+        self.buffer[self.pos] = input + self.store * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        out
+    }
+}
+
+/// Schroeder all-pass: smears the echoes without colouring the tone.
+#[derive(Clone, Debug)]
+struct AllPass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl AllPass {
+    fn new(len: usize) -> Self { Self { buffer: vec![0.0; len.max(1)], pos: 0 } }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = input + delayed * REVERB_ALLPASS_FEEDBACK;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// Minimal Freeverb: eight parallel combs into four all-passes. The
+/// sequencer runs one per channel, the right one with slightly longer
+/// delays so the tail is wide.
+#[derive(Clone, Debug)]
+pub struct Reverb {
+    /// 0..1, longer tail as it grows
+    pub room_size: f32,
+    /// 0..1, high frequencies die away faster as it grows
+    pub damping: f32,
+    /// Reverb level added to the dry signal (0 = off)
+    pub wet: f32,
+    combs: Vec<Comb>,
+    allpasses: Vec<AllPass>,
+}
+
+// Freeverb's tunings in samples at 44.1 kHz, rescaled for other rates
+const REVERB_COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const REVERB_ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+const REVERB_STEREO_SPREAD: usize = 23;
+const REVERB_ALLPASS_FEEDBACK: f32 = 0.5;
+// input level into the combs, and how room/damping map onto the loop
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_ROOM_SCALE: f32 = 0.28;
+const REVERB_ROOM_OFFSET: f32 = 0.7;
+const REVERB_DAMP_SCALE: f32 = 0.4;
+
+impl Reverb {
+    /// `spread` lengthens every delay (in 44.1 kHz samples) for the second
+    /// channel.
+    pub fn new(sample_rate: f32, spread: usize) -> Self {
+        let scale = |len: usize| ((len + spread) as f32 * sample_rate / 44100.0) as usize;
+        Self {
+            room_size: 0.5,
+            damping: 0.5,
+            wet: 0.0,
+            combs: REVERB_COMBS.iter().map(|&len| Comb::new(scale(len))).collect(),
+            allpasses: REVERB_ALLPASSES.iter().map(|&len| AllPass::new(scale(len))).collect(),
+        }
+    }
+
+    pub fn set(&mut self, room_size: f32, damping: f32, wet: f32) {
+        self.room_size = room_size.clamp(0.0, 1.0);
+        self.damping = damping.clamp(0.0, 1.0);
+        self.wet = wet.clamp(0.0, 1.0);
+    }
+
+    /// Dry input plus `wet` reverb.
+    pub fn process(&mut self, input: f32) -> f32 {
+        let feedback = self.room_size * REVERB_ROOM_SCALE + REVERB_ROOM_OFFSET;
+        let damp = self.damping * REVERB_DAMP_SCALE;
+        let x = input * REVERB_INPUT_GAIN;
+        let mut out: f32 = self.combs.iter_mut().map(|c| c.process(x, feedback, damp)).sum();
+        for ap in &mut self.allpasses {
+            out = ap.process(out);
+        }
+        input + out * self.wet
+    }
+}
+
+/// Last stage of the mix: master gain into a soft-knee limiter. Below the
+/// knee the signal passes untouched; above it peaks bend smoothly towards
+/// full scale instead of clipping.
+#[derive(Clone, Debug)]
+pub struct MasterBus {
+    pub gain: f32,
+}
+
+// level where the limiter starts to bend
+const LIMITER_KNEE: f32 = 0.6;
+
+impl Default for MasterBus {
+    fn default() -> Self { Self { gain: 1.0 } }
+}
+
+impl MasterBus {
+    pub fn process(&self, x: f32) -> f32 {
+        let x = x * self.gain;
+        let a = x.abs();
+        if a <= LIMITER_KNEE { return x; }
+        // tanh keeps slope 1 at the knee and never passes 1.0
+        let over = (a - LIMITER_KNEE) / (1.0 - LIMITER_KNEE);
+        x.signum() * (LIMITER_KNEE + (1.0 - LIMITER_KNEE) * over.tanh())
+    }
+}
+
+//
+// =========================
+//   L F O
+// =========================
+//
+
+/// What a track's LFO modulates.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LfoTarget {
+    /// Voice pitch; depth in semitones
+    Pitch,
+    /// Filter cutoff; depth in octaves
+    Cutoff,
+}
+
+impl LfoTarget {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "pitch" => Some(LfoTarget::Pitch),
+            "cutoff" | "lpf" => Some(LfoTarget::Cutoff),
+            _ => None,
+        }
+    }
+}
+
+/// Low-frequency oscillator. The settings live on the `Track`; the running
+/// phase is kept by the sequencer so modulation carries across steps.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Lfo {
+    pub target: LfoTarget,
+    /// Hz
+    pub rate: f32,
+    pub depth: f32,
+    pub waveform: Waveform,
+    #[serde(skip)]
+    pub phase: f32,
+}
+
+impl Lfo {
+    pub fn new(target: LfoTarget, rate: f32, depth: f32) -> Self {
+        Self { target, rate, depth, waveform: Waveform::Sine, phase: 0.0 }
+    }
+
+    /// Current output in -depth..depth, advancing one sample.
+    pub fn value(&mut self, sample_rate: f32) -> f32 {
+        let v = self.waveform.sample(self.phase) * self.depth;
+        self.phase = (self.phase + self.rate.max(0.0) / sample_rate).fract();
+        v
+    }
+}
+
+//
+// =========================
+//   A R P
+// =========================
+//
+
+/// Order an arpeggiator walks a chord in.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up then back down without repeating the top and bottom notes
+    UpDown,
+    Random,
+}
+
+impl ArpMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "up" => Some(ArpMode::Up),
+            "down" => Some(ArpMode::Down),
+            "updown" | "up-down" => Some(ArpMode::UpDown),
+            "random" | "rand" => Some(ArpMode::Random),
+            _ => None,
+        }
+    }
+}
+
+/// Plays a held chord one note at a time. `rate` is a note value
+/// (16 = sixteenths), never faster than one note per step.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Arp {
+    pub mode: ArpMode,
+    pub rate: usize,
+}
+
+impl Arp {
+    /// Steps each arp note lasts at `steps_per_beat` steps per quarter note.
+    pub fn steps_per_note(&self, steps_per_beat: usize) -> usize {
+        (steps_per_beat * 4 / self.rate.max(1)).max(1)
+    }
+
+    /// Which chord note (0 = lowest) the `pos`th arp note plays.
+    pub fn note_index(&self, pos: usize, len: usize, rng: &mut Rng) -> usize {
+        if len < 2 { return 0; }
+        match self.mode {
+            ArpMode::Up => pos % len,
+            ArpMode::Down => len - 1 - pos % len,
+            ArpMode::UpDown => {
+                let p = pos % (2 * len - 2);
+                if p < len { p } else { 2 * len - 2 - p }
+            }
+            ArpMode::Random => (rng.next_u64() % len as u64) as usize,
+        }
+    }
+}
+
+//
+// =========================
+//   S C A L E + UTILS
+// =========================
+//
+
+pub fn midi_to_freq(n: i32) -> f32 { 440.0 * 2f32.powf((n as f32 - 69.0)/12.0) }
+
+fn minor_scale(root: &str) -> Vec<i32> {
+    scale_from_name("minor", root).unwrap_or_default()
+}
+
+/// Named scale types as intervals from the root (major and minor first so
+/// they win ties in `best_fit_scale`)
+pub const SCALE_TYPES: &[(&str, [i32; 7])] = &[
+    ("major", [0,2,4,5,7,9,11]),
+    ("minor", [0,2,3,5,7,8,10]),
+    ("dorian", [0,2,3,5,7,9,10]),
+    ("phrygian", [0,1,3,5,7,8,10]),
+    ("lydian", [0,2,4,6,7,9,11]),
+    ("mixolydian", [0,2,4,5,7,9,10]),
+    ("locrian", [0,1,3,5,6,8,10]),
+];
+
+pub fn scale_intervals(mode: &str) -> Option<[i32; 7]> {
+    let mode = match mode.to_lowercase().as_str() {
+        "ionian" => "major".to_string(),
+        "aeolian" => "minor".to_string(),
+        m => m.to_string(),
+    };
+    SCALE_TYPES.iter().find(|(name, _)| *name == mode).map(|(_, i)| *i)
+}
+
+/// Semitones of a named mode at `root`, or None for an unknown mode.
+pub fn scale_from_name(mode: &str, root: &str) -> Option<Vec<i32>> {
+    let r = note_to_semitone(root);
+    scale_intervals(mode).map(|intervals| intervals.iter().map(|x| x + r).collect())
+}
+
+/// Scale type and root that best cover `notes` (absolute semitones).
+/// Ties favour roots that occur often, then earlier entries in SCALE_TYPES.
+pub fn best_fit_scale(notes: &[i32]) -> (String, String) {
+    let mut counts = [0usize; 12];
+    for n in notes { counts[n.rem_euclid(12) as usize] += 1; }
+
+    let mut best = ((0, 0), "major", 0);
+    for (name, intervals) in SCALE_TYPES {
+        for root in 0..12 {
+            let covered: usize = intervals.iter().map(|i| counts[((root + i) % 12) as usize]).sum();
+            let score = (covered, counts[root as usize]);
+            if score > best.0 { best = (score, name, root); }
+        }
+    }
+    (best.1.to_string(), semitone_to_note(best.2))
+}
+
+/// Semitone for a scale degree; degrees past the end of the scale
+/// continue into the next octave so scale intervals stay musical.
+pub fn degree_to_semitone(scale: &[i32], degree: i32) -> i32 {
+    if scale.is_empty() { return 0; }
+    let len = scale.len() as i32;
+    scale[degree.rem_euclid(len) as usize] + 12 * degree.div_euclid(len)
+}
+
+/// Same scale type moved to a new root semitone.
+fn rebuild_scale(scale: &[i32], root: i32) -> Vec<i32> {
+    let base = scale.first().copied().unwrap_or(0);
+    scale.iter().map(|n| n - base + root).collect()
+}
+
+fn note_to_semitone(name: &str) -> i32 {
+    match name.to_lowercase().as_str() {
+        "c"=>0,"c#"|"db"=>1,"d"=>2,"d#"|"eb"=>3,"e"=>4,"f"=>5,"f#"|"gb"=>6,
+        "g"=>7,"g#"|"ab"=>8,"a"=>9,"a#"|"bb"=>10,"b"=>11,_=>0
+    }
+}
+
+/// Pitch-class name for a semitone (any octave), using sharps.
+pub fn semitone_to_note(n: i32) -> String {
+    const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    NAMES[n.rem_euclid(12) as usize].to_string()
+}
+
+/// Small xorshift64* generator so random features are reproducible
+/// without pulling in a dependency.
+#[derive(Clone, Debug)]
+pub struct Rng { state: u64 }
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on an all-zero state
+        Self { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in 0.0..1.0
+    pub fn next_f32(&mut self) -> f32 { (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32 }
+
+    /// Uniform in -1.0..1.0
+    pub fn bipolar(&mut self) -> f32 { self.next_f32() * 2.0 - 1.0 }
+}
+
+//
+// =========================
+//   T R A C K
+// =========================
+//
+
+/// Level shape applied across each bar, on top of accents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum VelocityCurve {
+    #[default]
+    Flat,
+    /// Crescendo from half level to full over the bar
+    Ramp,
+    /// Full level falling to half over the bar
+    Fall,
+    /// One sine swell per bar around 0.75
+    Sine,
+}
+
+impl VelocityCurve {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "flat" => Some(Self::Flat),
+            "ramp" => Some(Self::Ramp),
+            "fall" => Some(Self::Fall),
+            "sine" => Some(Self::Sine),
+            _ => None,
+        }
+    }
+
+    /// Level multiplier at `pos` (0..1) through the bar
+    pub fn gain(self, pos: f32) -> f32 {
+        match self {
+            Self::Flat => 1.0,
+            Self::Ramp => 0.5 + 0.5 * pos,
+            Self::Fall => 1.0 - 0.5 * pos,
+            Self::Sine => 0.75 + 0.25 * (2.0 * PI * pos).sin(),
+        }
+    }
+}
+
+/// Pattern value for a rest (`-1` or `.` in the DSL). Both markers sit far
+/// below any real degree, so degrees under the root (from a harmony a
+/// third down, say) are still notes.
+pub const REST: i32 = i32::MIN;
+/// Pattern value that holds the previous note without retriggering (`~` in the DSL)
+pub const TIE: i32 = i32::MIN + 1;
+
+/// Whether a pattern value strikes a note, rather than resting or tying.
+pub fn is_note(step: i32) -> bool { step > TIE }
+
+/// One DSL pattern token: a degree, `-1` or `.` for a rest, or `~` for a
+/// tie. Typed patterns keep `-1` as their rest, so other negative degrees
+/// can be typed but -1 itself only comes from transforms like harmonize.
+pub fn parse_step(token: &str) -> Option<i32> {
+    if token == "-1" { Some(REST) } else { parse_saved_step(token) }
+}
+
+/// A step as saved by `pattern_text`, where every number is a degree.
+fn parse_saved_step(token: &str) -> Option<i32> {
+    match token {
+        "." => Some(REST),
+        "~" => Some(TIE),
+        _ => token.parse().ok().filter(|&n| is_note(n)),
+    }
+}
+
+/// A pattern as text, e.g. `0 . ~ -2`: rests are `.` and every number is a
+/// degree.
+pub fn pattern_text(pattern: &[i32]) -> String {
+    pattern.iter()
+        .map(|&n| match n {
+            REST => ".".to_string(),
+            TIE => "~".to_string(),
+            n => n.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Patterns are saved in DSL notation. Older files hold a list of numbers
+/// where -2 was a tie and any other negative a rest; those still load.
+mod pattern_serde {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: serde::Serializer>(pattern: &[i32], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&pattern_text(pattern))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<i32>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Saved {
+            Text(String),
+            Legacy(Vec<i32>),
+        }
+        match Saved::deserialize(d)? {
+            Saved::Text(text) => text.split_whitespace()
+                .map(|t| parse_saved_step(t).ok_or_else(|| D::Error::custom(format!("bad step '{t}' in pattern"))))
+                .collect(),
+            Saved::Legacy(steps) => Ok(steps.into_iter()
+                .map(|n| match n { -2 => TIE, n if n < 0 => REST, n => n })
+                .collect()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Track {
+    pub name: String,
+    #[serde(with = "pattern_serde")]
+    pub pattern: Vec<i32>,
+    pub octave: i32,
+    pub transpose: i32,
+    pub waveform: Waveform,
+    pub voice_spread: i32,
+    /// Per-step delay as a fraction of a step (missing entries = on the grid)
+    #[serde(default)]
+    pub step_offsets: Vec<f32>,
+    /// Per-step velocity 0..1 (missing entries = full velocity)
+    #[serde(default)]
+    pub velocities: Vec<f32>,
+    /// Per-step chance 0..1 that the step fires (missing entries = always)
+    #[serde(default)]
+    pub probabilities: Vec<f32>,
+    /// Per-step chord degrees; `pattern` holds the first of each. Missing
+    /// or empty entries are single notes.
+    #[serde(default)]
+    pub chords: Vec<Vec<i32>>,
+    /// Noise gate threshold in dBFS (None = no gate) and release in seconds
+    #[serde(default)]
+    pub gate_threshold: Option<f32>,
+    #[serde(default = "default_gate_release")]
+    pub gate_release: f32,
+    #[serde(default)]
+    pub velocity_curve: VelocityCurve,
+    /// Flip the polarity of this track in the mix
+    #[serde(default)]
+    pub phase_invert: bool,
+    /// Restart every voice's oscillator together on each trigger for a hard
+    /// attack (the default). Off (`.retrig(false)`), oscillators run free
+    /// across steps and only the envelope and pitch follow the notes, for
+    /// smoother legato. Older projects saved this as `phase_sync`.
+    #[serde(default = "default_true", alias = "phase_sync")]
+    pub retrig_phase: bool,
+    /// Silenced in the mix; saved with the project
+    #[serde(default)]
+    pub muted: bool,
+    /// Runtime-only: while any track is soloed, only soloed tracks sound
+    #[serde(skip)]
+    pub soloed: bool,
+    /// Linear level of this track in the mix
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    /// How much step velocity shapes the envelope peak/attack (0..1)
+    #[serde(default)]
+    pub vel_to_env: f32,
+    /// FM modulator frequency ratio and index (0 = no FM)
+    #[serde(default = "default_fm_ratio")]
+    pub fm_ratio: f32,
+    #[serde(default)]
+    pub fm_index: f32,
+    /// Low-pass cutoff in Hz (20000 = open) and resonance 0..1
+    #[serde(default = "default_filter_cutoff")]
+    pub filter_cutoff: f32,
+    #[serde(default)]
+    pub filter_resonance: f32,
+    /// Envelope times in seconds and sustain level 0..1
+    #[serde(default = "default_attack")]
+    pub attack: f32,
+    #[serde(default = "default_decay")]
+    pub decay: f32,
+    #[serde(default = "default_sustain")]
+    pub sustain: f32,
+    #[serde(default = "default_release")]
+    pub release: f32,
+    /// Stereo position, -1 = left, 0 = centre, 1 = right
+    #[serde(default)]
+    pub pan: f32,
+    /// Supersaw-style unison: the outer voices go this many cents flat and
+    /// sharp and spread across the stereo field (0 = off)
+    #[serde(default)]
+    pub detune_cents: f32,
+    /// How far the voices spread from centre, 0 (mono) to 1 (outer voices
+    /// hard to each side), detuned or not; independent of `pan`
+    #[serde(default = "default_unison_width")]
+    pub unison_width: f32,
+    /// Echo time in seconds, feedback 0..0.95 and wet mix 0..1 (0 = off)
+    #[serde(default)]
+    pub delay_time: f32,
+    #[serde(default)]
+    pub delay_feedback: f32,
+    #[serde(default)]
+    pub delay_mix: f32,
+    /// Portamento time in seconds between held notes (0 = off)
+    #[serde(default)]
+    pub glide: f32,
+    /// Fraction of its length a note holds before releasing; ties add whole
+    /// steps (1 = hold until the next note or rest)
+    #[serde(default = "default_note_gate")]
+    pub gate: f32,
+    /// Random timing and velocity wobble on every note, 0..1 (0 = exact)
+    #[serde(default)]
+    pub humanize: f32,
+    /// Bitcrusher depth (16 = off) and sample-and-hold factor (1 = off)
+    #[serde(default = "default_crush_bits")]
+    pub crush_bits: u8,
+    #[serde(default = "default_crush_downsample")]
+    pub crush_downsample: usize,
+    /// Optional modulation of pitch or cutoff
+    #[serde(default)]
+    pub lfo: Option<Lfo>,
+    /// Arpeggiate chords instead of striking them
+    #[serde(default)]
+    pub arp: Option<Arp>,
+}
+
+fn default_filter_cutoff() -> f32 { FILTER_OPEN }
+
+fn default_gain() -> f32 { 1.0 }
+
+fn default_crush_bits() -> u8 { CRUSH_BITS_OFF }
+
+fn default_crush_downsample() -> usize { 1 }
+
+fn default_fm_ratio() -> f32 { 1.0 }
+
+fn default_attack() -> f32 { DEFAULT_ATTACK }
+
+fn default_decay() -> f32 { DEFAULT_DECAY }
+
+fn default_sustain() -> f32 { DEFAULT_SUSTAIN }
+
+fn default_release() -> f32 { DEFAULT_RELEASE }
+
+pub fn db_to_gain(db: f32) -> f32 { 10f32.powf(db / 20.0) }
+
+fn default_gate_release() -> f32 { 0.1 }
+
+// the spread every track had before width was adjustable
+fn default_unison_width() -> f32 { 0.7 }
+fn default_true() -> bool { true }
+
+fn default_note_gate() -> f32 { 1.0 }
+
+impl Track {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            pattern: vec![0],
+            octave: 3,
+            transpose: 0,
+            waveform: Waveform::Saw,
+            voice_spread: 7,
+            step_offsets: Vec::new(),
+            velocities: Vec::new(),
+            probabilities: Vec::new(),
+            chords: Vec::new(),
+            gate_threshold: None,
+            gate_release: default_gate_release(),
+            velocity_curve: VelocityCurve::Flat,
+            phase_invert: false,
+            retrig_phase: true,
+            muted: false,
+            soloed: false,
+            gain: default_gain(),
+            vel_to_env: 0.0,
+            fm_ratio: default_fm_ratio(),
+            fm_index: 0.0,
+            filter_cutoff: FILTER_OPEN,
+            filter_resonance: 0.0,
+            attack: DEFAULT_ATTACK,
+            decay: DEFAULT_DECAY,
+            sustain: DEFAULT_SUSTAIN,
+            release: DEFAULT_RELEASE,
+            pan: 0.0,
+            detune_cents: 0.0,
+            unison_width: default_unison_width(),
+            delay_time: 0.0,
+            delay_feedback: 0.0,
+            delay_mix: 0.0,
+            glide: 0.0,
+            gate: default_note_gate(),
+            humanize: 0.0,
+            crush_bits: CRUSH_BITS_OFF,
+            crush_downsample: 1,
+            lfo: None,
+            arp: None,
+        }
+    }
+
+    /// Samples after the step boundary at which step `idx` fires.
+    pub fn step_delay(&self, idx: usize, samples_per_step: usize) -> usize {
+        let offset = self.step_offsets.get(idx).copied().unwrap_or(0.0);
+        (offset.clamp(0.0, MAX_STEP_OFFSET) * samples_per_step as f32) as usize
+    }
+
+    /// Degrees sounding on step `idx`: the chord if there is one, else the
+    /// pattern note alone.
+    pub fn step_chord(&self, idx: usize) -> Vec<i32> {
+        match self.chords.get(idx) {
+            Some(chord) if !chord.is_empty() => chord.clone(),
+            _ => self.pattern.get(idx).map(|&n| vec![n]).unwrap_or_default(),
+        }
+    }
+
+    /// Voices this track needs: the usual spread stack, or more for wide chords.
+    pub fn voice_count(&self) -> usize {
+        self.chords.iter().map(Vec::len).max().unwrap_or(0).max(BASE_VOICES)
+    }
+
+    /// MIDI note for each of `voices` voices on step `idx`. Chord notes go to
+    /// separate voices; once they run out the stack repeats a `voice_spread`
+    /// higher, so a single note gives the classic root/spread/2×spread.
+    pub fn voice_notes(&self, idx: usize, scale: &[i32], voices: usize) -> Vec<i32> {
+        self.stack_notes(&self.step_chord(idx), scale, voices)
+    }
+
+    /// MIDI notes for `voices` voices sharing the degrees in `chord`.
+    pub fn stack_notes(&self, chord: &[i32], scale: &[i32], voices: usize) -> Vec<i32> {
+        if chord.is_empty() { return Vec::new(); }
+        (0..voices).map(|i| {
+            let degree = chord[i % chord.len()];
+            let stack = (i / chord.len()) as i32;
+            degree_to_semitone(scale, degree) + self.transpose + self.octave * 12 + stack * self.voice_spread
+        }).collect()
+    }
+
+    /// Load this track's envelope and FM settings into a voice.
+    pub fn configure_voice(&self, v: &mut Voice) {
+        v.set_waveform(self.waveform);
+        v.set_adsr(self.attack, self.decay, self.sustain, self.release);
+        v.set_fm(self.fm_ratio, self.fm_index);
+    }
+
+    /// True if the bitcrusher would change the signal.
+    pub fn is_crushed(&self) -> bool {
+        self.crush_bits < CRUSH_BITS_OFF || self.crush_downsample > 1
+    }
+
+    /// Where voice `i` of `n` sits in the unison spread, -1..1.
+    pub fn unison_position(i: usize, n: usize) -> f32 {
+        if n < 2 { 0.0 } else { i as f32 / (n - 1) as f32 * 2.0 - 1.0 }
+    }
+
+    /// Equal-power (left, right) gains for `pan`; -3 dB each at centre.
+    pub fn pan_gains(&self) -> (f32, f32) {
+        let angle = (self.pan.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+        (angle.cos(), angle.sin())
+    }
+
+    /// Steps a note struck on step `idx` lasts: its own plus the ties
+    /// after it.
+    pub fn note_span(&self, idx: usize) -> usize {
+        let len = self.pattern.len();
+        1 + (1..len).take_while(|k| self.pattern[(idx + k) % len] == TIE).count()
+    }
+
+    /// The step whose note a tie on step `idx` is holding, if any.
+    pub fn held_step(&self, idx: usize) -> Option<usize> {
+        let len = self.pattern.len();
+        (1..len)
+            .map(|back| (idx + len - back) % len)
+            .find(|&i| self.pattern[i] != TIE)
+            .filter(|&i| is_note(self.pattern[i]))
+    }
+
+    /// Chance that step `idx` fires, 1.0 unless the pattern gave one.
+    pub fn step_probability(&self, idx: usize) -> f32 {
+        self.probabilities.get(idx).copied().unwrap_or(1.0)
+    }
+
+    /// Velocity of step `idx`, 1.0 unless the pattern gave one.
+    pub fn step_velocity(&self, idx: usize) -> f32 {
+        self.velocities.get(idx).copied().unwrap_or(1.0)
+    }
+
+    /// Fill any per-step lists that are in use out to the pattern length,
+    /// so reordering keeps them lined up with their steps.
+    fn pad_step_data(&mut self) {
+        let len = self.pattern.len();
+        if !self.step_offsets.is_empty() { self.step_offsets.resize(len, 0.0); }
+        if !self.velocities.is_empty() { self.velocities.resize(len, 1.0); }
+        if !self.probabilities.is_empty() { self.probabilities.resize(len, 1.0); }
+        if !self.chords.is_empty() { self.chords.resize(len, Vec::new()); }
+    }
+
+    /// Play the pattern backwards; per-step data moves with its step.
+    pub fn reverse(&mut self) {
+        self.pad_step_data();
+        self.pattern = reverse_pattern(&self.pattern);
+        self.step_offsets = reverse_pattern(&self.step_offsets);
+        self.velocities = reverse_pattern(&self.velocities);
+        self.probabilities = reverse_pattern(&self.probabilities);
+        self.chords = reverse_pattern(&self.chords);
+    }
+
+    /// Shift the pattern `n` steps later; per-step data moves with its step.
+    pub fn rotate(&mut self, n: isize) {
+        self.pad_step_data();
+        self.pattern = rotate_pattern(&self.pattern, n);
+        self.step_offsets = rotate_pattern(&self.step_offsets, n);
+        self.velocities = rotate_pattern(&self.velocities, n);
+        self.probabilities = rotate_pattern(&self.probabilities, n);
+        self.chords = rotate_pattern(&self.chords, n);
+    }
+
+    /// Mirror every note (chords included) within the scale.
+    pub fn invert(&mut self, scale_len: usize) {
+        self.pattern = invert_pattern(&self.pattern, scale_len);
+        for chord in &mut self.chords {
+            *chord = invert_pattern(chord, scale_len);
+        }
+    }
+
+    /// Swing the off-beat (odd) steps in `start..=end` by `amount` of a step.
+    pub fn swing_range(&mut self, start: usize, end: usize, amount: f32) {
+        let len = self.pattern.len();
+        self.step_offsets.resize(len, 0.0);
+        for idx in start..=end.min(len.saturating_sub(1)) {
+            if idx % 2 == 1 {
+                self.step_offsets[idx] = amount.clamp(0.0, MAX_STEP_OFFSET);
+            }
+        }
+    }
+}
+
+// side gain at unison width 1: an outer voice on a centred track then
+// cancels out of the far channel completely
+const UNISON_MAX_SIDE: f32 = FRAC_1_SQRT_2;
+
+// four octaves either way is already past most of the keyboard
+pub const MAX_TRANSPOSE_ALL: i32 = 48;
+
+// voices per track when no chord asks for more
+const BASE_VOICES: usize = 3;
+
+// a step can be pushed at most this far towards the next one
+const MAX_STEP_OFFSET: f32 = 0.75;
+
+/// A bass line following `src`'s step degrees (its chord roots), with
+/// rests carried through. Drops one or two octaves to land in bass range.
+pub fn bass_from_track(src: &Track, name: &str) -> Track {
+    let mut bass = Track::new(name);
+    bass.pattern = src.pattern.clone();
+    bass.transpose = src.transpose;
+    bass.octave = (src.octave - 2).max(2).min(src.octave - 1);
+    bass.waveform = Waveform::Sine;
+    bass
+}
+
+// default length of a generated pattern, and the longest allowed
+const RANDOM_STEPS: usize = 8;
+const MAX_RANDOM_STEPS: usize = 64;
+
+/// Random track of `RANDOM_STEPS` steps in a scale of `scale_len` degrees.
+pub fn random_track(name: &str, scale_len: usize, rng: &mut Rng) -> Track {
+    random_track_with_len(name, scale_len, RANDOM_STEPS, rng)
+}
+
+/// A playable random pattern: it starts on the root, leans on the root
+/// and fifth, rests about a third of the time and sometimes holds a note.
+/// Drawn from `rng` (the sequencer's), so a seed replays the same tracks.
+pub fn random_track_with_len(name: &str, scale_len: usize, steps: usize, rng: &mut Rng) -> Track {
+    // degree weights: root, fifth, third, then everything else
+    let weights: Vec<u64> = (0..scale_len.max(1))
+        .map(|d| match d { 0 => 4, 4 => 3, 2 => 2, _ => 1 })
+        .collect();
+    let total: u64 = weights.iter().sum();
+    let pick_degree = |rng: &mut Rng| {
+        let mut roll = rng.next_u64() % total;
+        weights.iter().position(|&w| {
+            if roll < w { return true; }
+            roll -= w;
+            false
+        }).unwrap_or(0) as i32
+    };
+
+    let mut track = Track::new(name);
+    track.pattern = vec![0];
+    for _ in 1..steps.clamp(1, MAX_RANDOM_STEPS) {
+        let r = rng.next_f32();
+        let holding = track.pattern.last().is_some_and(|&n| n != REST);
+        track.pattern.push(if r < 0.3 {
+            REST
+        } else if r < 0.4 && holding {
+            TIE
+        } else {
+            pick_degree(rng)
+        });
+    }
+    let waves = [Waveform::Sine, Waveform::Saw, Waveform::Square, Waveform::Triangle];
+    track.waveform = waves[(rng.next_u64() % waves.len() as u64) as usize];
+    track.octave = 3 + (rng.next_u64() % 3) as i32;
+    track
+}
+
+/// Copy of `src` moved up by a scale interval (3 = a third, 5 = a fifth),
+/// so each note stays in the scale. Rests and ties are kept.
+pub fn harmonize_track(src: &Track, interval: i32, name: &str) -> Track {
+    let shift = interval - interval.signum();
+    let mut harmony = src.clone();
+    harmony.name = name.to_string();
+    harmony.pattern = src.pattern.iter()
+        .map(|&n| if is_note(n) { n + shift } else { n })
+        .collect();
+    for chord in &mut harmony.chords {
+        chord.iter_mut().for_each(|n| *n += shift);
+    }
+    harmony
+}
+
+/// Scale degree (0-based) of a roman numeral chord symbol, e.g. "vi" -> 5.
+/// Case (major/minor quality) is ignored; the scale decides the quality.
+fn roman_to_degree(numeral: &str) -> Option<i32> {
+    match numeral.to_lowercase().as_str() {
+        "i" => Some(0), "ii" => Some(1), "iii" => Some(2), "iv" => Some(3),
+        "v" => Some(4), "vi" => Some(5), "vii" => Some(6),
+        _ => None,
+    }
+}
+
+/// One triad per bar, held with ties for the rest of the bar: the pattern
+/// holds each root and `chords` the stacked thirds over it.
+pub fn progression_pattern(numerals: &[&str], steps_per_bar: usize) -> Option<(Vec<i32>, Vec<Vec<i32>>)> {
+    let hold = steps_per_bar.saturating_sub(1);
+    let mut pattern = Vec::new();
+    let mut chords = Vec::new();
+    for numeral in numerals {
+        let root = roman_to_degree(numeral)?;
+        pattern.push(root);
+        pattern.extend(std::iter::repeat_n(TIE, hold));
+        chords.push(vec![root, root + 2, root + 4]);
+        chords.extend(std::iter::repeat_n(Vec::new(), hold));
+    }
+    Some((pattern, chords))
+}
+
+fn gcd(a: usize, b: usize) -> usize { if b == 0 { a } else { gcd(b, a % b) } }
+
+/// Overlay two patterns step by step: a note beats a rest, and when both
+/// have a note the first pattern wins. Unequal lengths are extended to
+/// their LCM so both patterns complete whole cycles.
+pub fn combine_patterns(a: &[i32], b: &[i32]) -> Vec<i32> {
+    if a.is_empty() { return b.to_vec(); }
+    if b.is_empty() { return a.to_vec(); }
+    let len = a.len() / gcd(a.len(), b.len()) * b.len();
+    (0..len).map(|i| {
+        let (na, nb) = (a[i % a.len()], b[i % b.len()]);
+        if is_note(na) || !is_note(nb) { na } else { nb }
+    }).collect()
+}
+
+/// Steps in reverse order.
+fn reverse_pattern<T: Clone>(steps: &[T]) -> Vec<T> {
+    steps.iter().rev().cloned().collect()
+}
+
+/// Steps shifted `n` places later (earlier if negative), wrapping around.
+fn rotate_pattern<T: Clone>(steps: &[T], n: isize) -> Vec<T> {
+    let mut out = steps.to_vec();
+    let len = out.len() as isize;
+    if len > 0 {
+        out.rotate_right(n.rem_euclid(len) as usize);
+    }
+    out
+}
+
+/// Mirror each degree within its octave of the scale (degree d becomes
+/// scale_len-1-d), so the melody's contour flips. Rests and ties stay.
+fn invert_pattern(steps: &[i32], scale_len: usize) -> Vec<i32> {
+    let len = scale_len.max(1) as i32;
+    steps.iter()
+        .map(|&d| if is_note(d) { d.div_euclid(len) * len + (len - 1 - d.rem_euclid(len)) } else { d })
+        .collect()
+}
+
+//
+// =========================
+//   S E Q U E N C E R
+// =========================
+//
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectData {
+    pub tracks: Vec<Track>,
+    pub scale: Vec<i32>,
+    pub bpm: f32,
+    /// Scale mode and root name; when present they rebuild `scale` on load
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub root: Option<String>,
+    /// Semitones added to every track on top of its own `transpose`
+    #[serde(default)]
+    pub transpose_all: i32,
+    /// Global swing, 0 = straight (see `Sequencer::swing`)
+    #[serde(default)]
+    pub swing: f32,
+    /// Steps per quarter note (4 = 16ths)
+    #[serde(default = "default_steps_per_beat")]
+    pub steps_per_beat: usize,
+    /// Time signature, e.g. 7 and 8 for 7/8
+    #[serde(default = "default_time_sig_num")]
+    pub time_sig_num: usize,
+    #[serde(default = "default_time_sig_den")]
+    pub time_sig_den: usize,
+    /// Linear gain into the master limiter
+    #[serde(default = "default_gain")]
+    pub master_gain: f32,
+    /// Seed for every random feature (see `Sequencer::seed`)
+    #[serde(default = "default_seed")]
+    pub seed: u64,
+    /// Master reverb room size, damping and wet level (wet 0 = off)
+    #[serde(default = "default_reverb_room")]
+    pub reverb_room: f32,
+    #[serde(default = "default_reverb_damping")]
+    pub reverb_damping: f32,
+    #[serde(default)]
+    pub reverb_wet: f32,
+    /// Saved sections and the order they play in (empty = just loop)
+    #[serde(default)]
+    pub sections: Vec<Section>,
+    #[serde(default)]
+    pub arrangement: Arrangement,
+}
+
+fn default_steps_per_beat() -> usize { DEFAULT_STEPS_PER_BEAT }
+fn default_time_sig_num() -> usize { DEFAULT_BEATS_PER_BAR }
+fn default_time_sig_den() -> usize { DEFAULT_BEAT_UNIT }
+
+fn default_seed() -> u64 { DEFAULT_SEED }
+
+fn default_reverb_room() -> f32 { 0.5 }
+
+fn default_reverb_damping() -> f32 { 0.5 }
+
+impl ProjectData {
+    /// The project's scale, rebuilt from mode + root when both were saved
+    /// and the mode is one we know.
+    pub fn resolved_scale(&self) -> Vec<i32> {
+        match (&self.mode, &self.root) {
+            (Some(mode), Some(root)) => scale_from_name(mode, root).unwrap_or_else(|| self.scale.clone()),
+            _ => self.scale.clone(),
+        }
+    }
+}
+
+/// A track with the per-track state that owns memory (voices, effect
+/// buffers, filters), built in one piece so it can be made away from the
+/// audio thread and handed over ready to play.
+#[derive(Clone, Debug)]
+pub struct TrackParts {
+    pub track: Track,
+    voices: Vec<Voice>,
+    stutter: Stutter,
+    delay: Delay,
+    filter: Filter,
+    side_filter: Filter,
+}
+
+impl TrackParts {
+    pub fn new(track: Track, sample_rate: f32) -> Self {
+        Self {
+            voices: voice_pool(track.voice_count()),
+            stutter: Stutter::new(sample_rate),
+            delay: Delay::new(sample_rate),
+            filter: Filter::new(sample_rate),
+            side_filter: Filter::new(sample_rate),
+            track,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sequencer {
+    pub tracks: Vec<Track>,
+    pub scale: Vec<i32>,
+    /// (mode, root) the scale was built from; None for raw interval lists
+    pub scale_name: Option<(String, String)>,
+    /// Chromatic shift for every track in semitones. Unlike a scale
+    /// change it moves notes, not degrees.
+    pub transpose_all: i32,
+    pub voices: Vec<Vec<Voice>>,
+
+    pub sample_rate: f32,
+    /// Each track's position in its own pattern, parallel to `tracks`.
+    /// They all advance on the same step clock but wrap at their own
+    /// length, so patterns of different lengths run as a polymeter.
+    pub steps: Vec<usize>,
+    /// Tempo in quarter notes per minute; `samples_per_step` follows it
+    pub bpm: f32,
+    pub samples_per_step: usize,
+    pub sample_counter: usize,
+
+    /// Time signature, e.g. 7/8; the denominator sets how long a beat is
+    pub time_sig_num: usize,
+    pub time_sig_den: usize,
+    /// Steps per quarter note: 4 = 16ths, 3 = 8th triplets. Sets the step
+    /// length together with `bpm`.
+    pub steps_per_beat: usize,
+    /// Position within the current bar, independent of pattern lengths
+    pub bar_step: usize,
+    /// Bars completed since playback started
+    pub bar: usize,
+    /// Pending timed changes, sorted by position
+    pub schedule: Vec<ScheduledChange>,
+    /// Tempo glide in progress, stepped once per bar
+    pub ramp: Option<TempoRamp>,
+    /// Saved sections and the song order; patterns swap at bar lines
+    pub sections: Vec<Section>,
+    pub arrangement: Arrangement,
+    /// Bar the song started (or starts) on; None while just looping
+    song_start: Option<usize>,
+    /// Arrangement entry whose patterns are on the tracks
+    playing_entry: Option<usize>,
+
+    /// Delay of every off-beat step as a fraction of a step (0 = straight).
+    /// Each pair of steps keeps its length, so bars stay in time.
+    pub swing: f32,
+
+    /// Extra level on steps that start a beat (0 = no emphasis)
+    pub auto_accent: f32,
+
+    /// Maximum analog pitch drift in cents (0 = perfectly tuned)
+    pub analog_drift: f32,
+    /// Seconds a voice takes to smooth over a waveform switch or phase
+    /// reset (0 = off)
+    pub declick: f32,
+    /// Where all randomness starts: step probabilities and drift draw from
+    /// `rng`, noise voices get seeds derived from it. Rewinding restarts
+    /// from the seed, so a render is the same every time; a new seed
+    /// reshuffles which probabilistic steps play.
+    pub seed: u64,
+    pub rng: Rng,
+
+    /// Output level per track, parallel to `tracks`
+    pub meters: Vec<Meter>,
+    /// Live-only repeat buffers, parallel to `tracks`
+    pub stutters: Vec<Stutter>,
+    /// Echo per track, parallel to `tracks`
+    pub delays: Vec<Delay>,
+    /// Bitcrusher per track, parallel to `tracks`
+    pub crushers: Vec<BitCrush>,
+    /// Noise gate state, parallel to `tracks`
+    pub gates: Vec<NoiseGate>,
+    /// Low-pass filter per track, parallel to `tracks`, and a twin for
+    /// the unison side signal
+    pub filters: Vec<Filter>,
+    side_filters: Vec<Filter>,
+    /// Running LFO phase per track, parallel to `tracks`
+    pub lfo_phases: Vec<f32>,
+    /// Steps each track's arpeggiator has run, parallel to `tracks`
+    arp_steps: Vec<usize>,
+    /// Samples until each track's gate releases its notes, parallel to
+    /// `tracks` (None = nothing scheduled)
+    note_offs: Vec<Option<usize>>,
+    /// This step's random lateness per humanized track, parallel to `tracks`
+    human_delays: Vec<usize>,
+
+    /// Bounds applied to every voice frequency at trigger time
+    pub freq_min: f32,
+    pub freq_max: f32,
+
+    /// One-shot preview notes mixed over the tracks
+    pub audition: Audition,
+    /// Keyboard-played voice (see `jam_mode`)
+    pub jam: Jam,
+
+    /// Click on every beat (live only; offline renders turn it off)
+    pub metronome: bool,
+    click: Click,
+
+    /// Master high-cut in Hz (0 = off)
+    pub master_cut: f32,
+    master_filter: [Biquad; 2],
+    /// Master gain and limiter after everything else
+    pub master: MasterBus,
+    /// Master reverb, one per channel (see `Reverb`)
+    pub reverb: [Reverb; 2],
+}
+
+/// Peak/RMS level of a track's contribution to the mix.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Meter {
+    pub peak: f32,
+    mean_square: f32,
+}
+
+// ballistics time constant for both peak release and RMS averaging
+const METER_TIME: f32 = 0.3;
+
+impl Meter {
+    /// `coeff` is the per-sample smoothing factor (see `METER_TIME`)
+    pub fn update(&mut self, x: f32, coeff: f32) {
+        let a = x.abs();
+        self.peak = if a > self.peak { a } else { self.peak - (self.peak - a) * coeff };
+        self.mean_square += (x * x - self.mean_square) * coeff;
+    }
+
+    pub fn rms(&self) -> f32 { self.mean_square.sqrt() }
+}
+
+/// Momentary repeat of a track's recent output. The live signal is always
+/// recorded so the pattern keeps running underneath and playback resumes
+/// in time once the repeat ends.
+#[derive(Clone, Debug)]
+pub struct Stutter {
+    history: Vec<f32>,
+    write_pos: usize,
+    slice: Vec<f32>,
+    play_pos: usize,
+    remaining: usize,
+}
+
+// longest slice that can be captured
+const STUTTER_MAX_SECS: f32 = 2.0;
+const DEFAULT_STEPS_PER_BEAT: usize = 4;
+const DEFAULT_BEATS_PER_BAR: usize = 4;
+const DEFAULT_BEAT_UNIT: usize = 4;
+pub const MAX_STEPS_PER_BEAT: usize = 32;
+
+impl Stutter {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            history: vec![0.0; (sample_rate * STUTTER_MAX_SECS) as usize],
+            write_pos: 0,
+            slice: Vec::new(),
+            play_pos: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Capture the last `len` samples and loop them for `duration` samples.
+    pub fn trigger(&mut self, len: usize, duration: usize) {
+        let len = len.clamp(1, self.history.len());
+        let cap = self.history.len();
+        self.slice = (0..len)
+            .map(|i| self.history[(self.write_pos + cap - len + i) % cap])
+            .collect();
+        self.play_pos = 0;
+        self.remaining = duration;
+    }
+
+    pub fn is_active(&self) -> bool { self.remaining > 0 }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.history[self.write_pos] = x;
+        self.write_pos = (self.write_pos + 1) % self.history.len();
+        if self.remaining == 0 { return x; }
+        self.remaining -= 1;
+        let out = self.slice[self.play_pos];
+        self.play_pos = (self.play_pos + 1) % self.slice.len();
+        out
+    }
+}
+
+/// A short run of notes played over the mix, e.g. to hear a new key.
+#[derive(Clone, Debug, Default)]
+pub struct Audition {
+    voice: Voice,
+    notes: Vec<f32>,
+    note_len: usize,
+    pos: usize,
+    counter: usize,
+}
+
+impl Audition {
+    /// Start playing `notes`, handing back the previous run.
+    pub fn play(&mut self, notes: Vec<f32>, note_len: usize) -> Vec<f32> {
+        let old = std::mem::replace(&mut self.notes, notes);
+        self.note_len = note_len.max(1);
+        self.pos = 0;
+        self.counter = 0;
+        self.voice.waveform = Waveform::Sine;
+        if let Some(&f) = self.notes.first() {
+            self.voice.set_frequency(f);
+            self.voice.reset_env();
+        }
+        old
+    }
+
+    pub fn process(&mut self, sample_rate: f32) -> f32 {
+        if self.pos >= self.notes.len() { return 0.0; }
+        self.counter += 1;
+        if self.counter >= self.note_len {
+            self.counter = 0;
+            self.pos += 1;
+            let Some(&f) = self.notes.get(self.pos) else { return 0.0 };
+            self.voice.set_frequency(f);
+            self.voice.reset_env();
+        }
+        self.voice.process(sample_rate)
+    }
+}
+
+/// Live voice for jam mode. Terminals report key presses but not
+/// releases, so each note is a one-shot: held for `JAM_NOTE_SECS`, then
+/// released.
+#[derive(Clone, Debug, Default)]
+pub struct Jam {
+    voice: Voice,
+    hold: usize,
+}
+
+// how long a jam note is held before its release starts
+const JAM_NOTE_SECS: f32 = 0.25;
+
+impl Jam {
+    pub fn note_on(&mut self, freq: f32, waveform: Waveform, sample_rate: f32) {
+        self.voice.waveform = waveform;
+        self.voice.set_adsr(0.005, 0.1, 0.7, 0.3);
+        self.voice.set_frequency(freq);
+        self.voice.reset_env();
+        self.hold = (JAM_NOTE_SECS * sample_rate) as usize;
+    }
+
+    pub fn process(&mut self, sample_rate: f32) -> f32 {
+        if self.hold > 0 {
+            self.hold -= 1;
+            if self.hold == 0 { self.voice.note_off(); }
+        }
+        self.voice.process(sample_rate)
+    }
+}
+
+/// Metronome tick: a sine burst with a fast exponential decay.
+#[derive(Clone, Debug, Default)]
+pub struct Click {
+    phase: f32,
+    freq: f32,
+    level: f32,
+}
+
+// click loudness (bar accent; other beats get half) and decay time constant
+const CLICK_LEVEL: f32 = 0.3;
+const CLICK_DECAY: f32 = 0.01;
+
+impl Click {
+    /// Higher and louder on the downbeat, a softer tick on other beats.
+    pub fn trigger(&mut self, downbeat: bool) {
+        self.phase = 0.0;
+        (self.freq, self.level) = if downbeat { (1500.0, CLICK_LEVEL) } else { (1000.0, CLICK_LEVEL * 0.5) };
+    }
+
+    pub fn process(&mut self, sample_rate: f32) -> f32 {
+        if self.level < 1e-4 { return 0.0; }
+        let out = (2.0 * PI * self.phase).sin() * self.level;
+        self.phase = (self.phase + self.freq / sample_rate).fract();
+        self.level *= (-1.0 / (CLICK_DECAY * sample_rate)).exp();
+        out
+    }
+}
+
+pub fn to_db(x: f32) -> f32 { 20.0 * x.max(1e-6).log10() }
+
+// lowest voice frequency by default; the default ceiling is half Nyquist
+const DEFAULT_FREQ_MIN: f32 = 20.0;
+
+// humanize at 1: notes up to this late, velocity off by up to this fraction
+const HUMANIZE_MAX_SECS: f32 = 0.015;
+const HUMANIZE_VELOCITY: f32 = 0.3;
+
+/// A parameter change that takes effect at a given musical position.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamChange {
+    Bpm(f32),
+    MasterCut(f32),
+    Drift(f32),
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduledChange {
+    /// 1-based bar and beat, as typed in `at 8:1 ...`
+    pub bar: usize,
+    pub beat: usize,
+    pub change: ParamChange,
+}
+
+/// Linear tempo change from `from` to `to` BPM over `bars` bars.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempoRamp {
+    pub from: f32,
+    pub to: f32,
+    pub bars: usize,
+    /// `Sequencer::bar` when the ramp began
+    pub start_bar: usize,
+}
+
+/// One track's steps within a section.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SectionPart {
+    #[serde(with = "pattern_serde")]
+    pub pattern: Vec<i32>,
+    #[serde(default)]
+    pub step_offsets: Vec<f32>,
+    #[serde(default)]
+    pub velocities: Vec<f32>,
+    #[serde(default)]
+    pub probabilities: Vec<f32>,
+    #[serde(default)]
+    pub chords: Vec<Vec<i32>>,
+}
+
+impl SectionPart {
+    fn from_track(track: &Track) -> Self {
+        Self {
+            pattern: track.pattern.clone(),
+            step_offsets: track.step_offsets.clone(),
+            velocities: track.velocities.clone(),
+            probabilities: track.probabilities.clone(),
+            chords: track.chords.clone(),
+        }
+    }
+
+    /// Put these steps on `track`; its sound settings stay as they are.
+    fn apply_to(&self, track: &mut Track) {
+        track.pattern = self.pattern.clone();
+        track.step_offsets = self.step_offsets.clone();
+        track.velocities = self.velocities.clone();
+        track.probabilities = self.probabilities.clone();
+        track.chords = self.chords.clone();
+    }
+}
+
+/// A named set of track patterns, e.g. "intro" or "drop". Tracks it has
+/// no part for rest while it plays.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    pub name: String,
+    pub parts: BTreeMap<String, SectionPart>,
+}
+
+/// Song order: section names with how many bars each plays. The song
+/// loops back to the first entry after the last.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Arrangement {
+    pub sections: Vec<(String, usize)>,
+}
+
+impl Arrangement {
+    pub fn total_bars(&self) -> usize { self.sections.iter().map(|(_, bars)| bars).sum() }
+
+    /// Entry playing `bars` bars into the song.
+    fn entry_at(&self, bars: usize) -> Option<usize> {
+        let total = self.total_bars();
+        if total == 0 { return None; }
+        let mut pos = bars % total;
+        self.sections.iter().position(|&(_, len)| {
+            if pos < len { return true; }
+            pos -= len;
+            false
+        })
+    }
+}
+
+// rate for offline renders, and when no device reports one
+pub const DEFAULT_SAMPLE_RATE: f32 = 44100.0;
+
+fn samples_per_step_for(sample_rate: f32, bpm: f32, steps_per_beat: usize) -> usize {
+    (sample_rate * 60.0 / bpm / steps_per_beat.max(1) as f32) as usize
+}
+
+// seed of a fresh sequencer, and of projects saved before seeds existed
+const DEFAULT_SEED: u64 = 1;
+
+/// Noise seed for voice `voice` of track `track`, spread out so
+/// neighbouring voices don't start from related states.
+fn noise_seed(seed: u64, track: usize, voice: usize) -> u64 {
+    let id = ((track as u64) << 16 | voice as u64).wrapping_add(1);
+    (seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15)).wrapping_mul(0xBF58_476D_1CE4_E5B9)
+}
+
+// what a fresh sequencer plays at (one step per 1/4 second)
+const DEFAULT_BPM: f32 = 60.0;
+
+// beyond this the off-beat would almost land on the next downbeat
+pub const MAX_SWING: f32 = 0.75;
+
+// drift is a control-rate effect, no need to walk it every sample
+
+const DRIFT_INTERVAL: usize = 256;
+const DRIFT_STEP: f32 = 0.05;
+const DRIFT_LEAK: f32 = 0.01;
+
+impl Sequencer {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            tracks: vec![Track::new("Main")],
+            scale: minor_scale("g"),
+            scale_name: Some(("minor".to_string(), "g".to_string())),
+            transpose_all: 0,
+            voices: vec![voice_pool(BASE_VOICES)],
+            sample_rate,
+            steps: vec![0],
+            bpm: DEFAULT_BPM,
+            samples_per_step: samples_per_step_for(sample_rate, DEFAULT_BPM, DEFAULT_STEPS_PER_BEAT),
+            sample_counter: 0,
+            time_sig_num: DEFAULT_BEATS_PER_BAR,
+            time_sig_den: DEFAULT_BEAT_UNIT,
+            steps_per_beat: DEFAULT_STEPS_PER_BEAT,
+            bar_step: 0,
+            bar: 0,
+            schedule: Vec::new(),
+            ramp: None,
+            sections: Vec::new(),
+            arrangement: Arrangement::default(),
+            song_start: None,
+            playing_entry: None,
+            swing: 0.0,
+            auto_accent: 0.0,
+            analog_drift: 0.0,
+            declick: DEFAULT_DECLICK,
+            seed: DEFAULT_SEED,
+            rng: Rng::new(DEFAULT_SEED),
+            meters: vec![Meter::default()],
+            freq_min: DEFAULT_FREQ_MIN,
+            freq_max: sample_rate / 4.0,
+            stutters: vec![Stutter::new(sample_rate)],
+            delays: vec![Delay::new(sample_rate)],
+            crushers: vec![BitCrush::default()],
+            gates: vec![NoiseGate::default()],
+            filters: vec![Filter::new(sample_rate)],
+            side_filters: vec![Filter::new(sample_rate)],
+            lfo_phases: vec![0.0],
+            arp_steps: vec![0],
+            note_offs: vec![None],
+            human_delays: vec![0],
+            audition: Audition::default(),
+            jam: Jam::default(),
+            metronome: false,
+            click: Click::default(),
+            master_cut: 0.0,
+            master_filter: [Biquad::new(), Biquad::new()],
+            master: MasterBus::default(),
+            reverb: [Reverb::new(sample_rate, 0), Reverb::new(sample_rate, REVERB_STEREO_SPREAD)],
+        }
+    }
+
+    pub fn from_project(project: ProjectData, sample_rate: f32) -> Self {
+        let num_tracks = project.tracks.len();
+        let voices = project.tracks.iter().map(|t| {
+            let mut pool = voice_pool(t.voice_count());
+            pool.iter_mut().for_each(|v| t.configure_voice(v));
+            pool
+        }).collect();
+        
+        let scale = project.resolved_scale();
+        let steps_per_beat = project.steps_per_beat.clamp(1, MAX_STEPS_PER_BEAT);
+        let seed = project.seed;
+        let mut seq = Self {
+            tracks: project.tracks,
+            scale,
+            scale_name: project.mode.zip(project.root),
+            transpose_all: project.transpose_all,
+            voices,
+            sample_rate,
+            steps: vec![0; num_tracks],
+            bpm: project.bpm,
+            samples_per_step: samples_per_step_for(sample_rate, project.bpm, steps_per_beat),
+            sample_counter: 0,
+            time_sig_num: DEFAULT_BEATS_PER_BAR,
+            time_sig_den: DEFAULT_BEAT_UNIT,
+            steps_per_beat,
+            bar_step: 0,
+            bar: 0,
+            schedule: Vec::new(),
+            ramp: None,
+            sections: Vec::new(),
+            arrangement: Arrangement::default(),
+            song_start: None,
+            playing_entry: None,
+            swing: project.swing.clamp(0.0, MAX_SWING),
+            auto_accent: 0.0,
+            analog_drift: 0.0,
+            declick: DEFAULT_DECLICK,
+            seed,
+            rng: Rng::new(seed),
+            meters: vec![Meter::default(); num_tracks],
+            freq_min: DEFAULT_FREQ_MIN,
+            freq_max: sample_rate / 4.0,
+            stutters: vec![Stutter::new(sample_rate); num_tracks],
+            delays: vec![Delay::new(sample_rate); num_tracks],
+            crushers: vec![BitCrush::default(); num_tracks],
+            gates: vec![NoiseGate::default(); num_tracks],
+            filters: vec![Filter::new(sample_rate); num_tracks],
+            side_filters: vec![Filter::new(sample_rate); num_tracks],
+            lfo_phases: vec![0.0; num_tracks],
+            arp_steps: vec![0; num_tracks],
+            note_offs: vec![None; num_tracks],
+            human_delays: vec![0; num_tracks],
+            audition: Audition::default(),
+            jam: Jam::default(),
+            metronome: false,
+            click: Click::default(),
+            master_cut: 0.0,
+            master_filter: [Biquad::new(), Biquad::new()],
+            master: MasterBus { gain: project.master_gain.max(0.0) },
+            reverb: [Reverb::new(sample_rate, 0), Reverb::new(sample_rate, REVERB_STEREO_SPREAD)],
+        };
+        seq.set_reverb(project.reverb_room, project.reverb_damping, project.reverb_wet);
+        // a signature that doesn't fit the saved resolution stays 4/4
+        seq.set_time_signature(project.time_sig_num, project.time_sig_den);
+        seq.reseed(seed);
+        seq.sections = project.sections;
+        seq.set_arrangement(project.arrangement);
+        seq
+    }
+
+    /// Swap in a loaded project's tracks, scale and tempo while keeping the
+    /// engine settings (drift, clamps, master cut...) as they are.
+    pub fn replace_project(&mut self, mut project: ProjectData) {
+        project.scale = project.resolved_scale();
+        let mut parts = project.tracks.drain(..)
+            .map(|t| TrackParts::new(t, self.sample_rate))
+            .collect();
+        self.swap_project(&mut project, &mut parts);
+    }
+
+    /// `replace_project` with the tracks already built and `project.scale`
+    /// already resolved. Afterwards `project` and `parts` hold what was
+    /// replaced (scale, sections, arrangement, tracks), so the caller
+    /// decides where that memory is freed.
+    pub fn swap_project(&mut self, project: &mut ProjectData, parts: &mut Vec<TrackParts>) {
+        self.swap_tracks(parts);
+        std::mem::swap(&mut self.scale, &mut project.scale);
+        let name = project.mode.take().zip(project.root.take());
+        (project.mode, project.root) = std::mem::replace(&mut self.scale_name, name).unzip();
+        self.transpose_all = project.transpose_all;
+        self.steps_per_beat = project.steps_per_beat.clamp(1, MAX_STEPS_PER_BEAT);
+        (self.time_sig_num, self.time_sig_den) = (DEFAULT_BEATS_PER_BAR, DEFAULT_BEAT_UNIT);
+        self.set_time_signature(project.time_sig_num, project.time_sig_den);
+        self.set_bpm(project.bpm);
+        self.swing = project.swing.clamp(0.0, MAX_SWING);
+        self.master.gain = project.master_gain.max(0.0);
+        self.set_reverb(project.reverb_room, project.reverb_damping, project.reverb_wet);
+        self.reseed(project.seed);
+        self.steps.iter_mut().for_each(|n| *n = 0);
+        self.bar_step = 0;
+        self.bar = 0;
+        self.sample_counter = 0;
+        std::mem::swap(&mut self.sections, &mut project.sections);
+        let arrangement = std::mem::replace(&mut project.arrangement, std::mem::take(&mut self.arrangement));
+        self.set_arrangement(arrangement);
+    }
+
+    pub fn add_track(&mut self, track: Track) {
+        self.add_parts(TrackParts::new(track, self.sample_rate));
+    }
+
+    /// Add a track built ahead of time. The rest of its state is small and
+    /// goes into lists `reserve_tracks` can keep from growing.
+    pub fn add_parts(&mut self, parts: TrackParts) {
+        // join in step with the bar rather than from wherever the others are
+        self.steps.push(self.bar_step % parts.track.pattern.len().max(1));
+        self.voices.push(parts.voices);
+        self.tracks.push(parts.track);
+        self.meters.push(Meter::default());
+        self.stutters.push(parts.stutter);
+        self.delays.push(parts.delay);
+        self.crushers.push(BitCrush::default());
+        self.gates.push(NoiseGate::default());
+        self.filters.push(parts.filter);
+        self.side_filters.push(parts.side_filter);
+        self.lfo_phases.push(0.0);
+        self.arp_steps.push(0);
+        self.note_offs.push(None);
+        self.human_delays.push(0);
+    }
+
+    /// Remove a track, handing back its parts so the caller decides where
+    /// their memory is freed.
+    pub fn remove_track(&mut self, idx: usize) -> TrackParts {
+        let parts = TrackParts {
+            track: self.tracks.remove(idx),
+            voices: self.voices.remove(idx),
+            stutter: self.stutters.remove(idx),
+            delay: self.delays.remove(idx),
+            filter: self.filters.remove(idx),
+            side_filter: self.side_filters.remove(idx),
+        };
+        self.meters.remove(idx);
+        self.crushers.remove(idx);
+        self.gates.remove(idx);
+        self.lfo_phases.remove(idx);
+        self.arp_steps.remove(idx);
+        self.note_offs.remove(idx);
+        self.human_delays.remove(idx);
+        self.steps.remove(idx);
+        parts
+    }
+
+    /// Room for `extra` more tracks in every per-track list, so adding
+    /// them later doesn't reallocate.
+    pub fn reserve_tracks(&mut self, extra: usize) {
+        self.tracks.reserve(extra);
+        self.voices.reserve(extra);
+        self.meters.reserve(extra);
+        self.stutters.reserve(extra);
+        self.delays.reserve(extra);
+        self.crushers.reserve(extra);
+        self.gates.reserve(extra);
+        self.filters.reserve(extra);
+        self.side_filters.reserve(extra);
+        self.lfo_phases.reserve(extra);
+        self.arp_steps.reserve(extra);
+        self.note_offs.reserve(extra);
+        self.human_delays.reserve(extra);
+        self.steps.reserve(extra);
+    }
+
+    /// Swap the whole track list for `parts`, which comes back holding the
+    /// old tracks in order. Nothing is allocated as long as `parts` has
+    /// room for the old list and the per-track lists for the new one.
+    pub fn swap_tracks(&mut self, parts: &mut Vec<TrackParts>) {
+        let incoming = parts.len();
+        while let Some(last) = self.tracks.len().checked_sub(1) {
+            parts.push(self.remove_track(last));
+        }
+        for p in parts.drain(..incoming) {
+            self.add_parts(p);
+        }
+        parts.reverse();
+    }
+
+    pub fn clear_tracks(&mut self) {
+        self.tracks.clear();
+        self.voices.clear();
+        self.meters.clear();
+        self.stutters.clear();
+        self.delays.clear();
+        self.crushers.clear();
+        self.gates.clear();
+        self.filters.clear();
+        self.side_filters.clear();
+        self.lfo_phases.clear();
+        self.arp_steps.clear();
+        self.note_offs.clear();
+        self.human_delays.clear();
+        self.steps.clear();
+    }
+
+    /// Queue a change for bar:beat (1-based), keeping the schedule sorted.
+    pub fn schedule_change(&mut self, bar: usize, beat: usize, change: ParamChange) {
+        let pos = self.schedule.partition_point(|c| (c.bar, c.beat) <= (bar, beat));
+        self.schedule.insert(pos, ScheduledChange { bar, beat, change });
+    }
+
+    /// Apply every scheduled change whose position has been reached.
+    fn apply_due_changes(&mut self) {
+        let now = (self.bar + 1, self.bar_step / self.steps_per_sig_beat() + 1);
+        while self.schedule.first().is_some_and(|c| (c.bar, c.beat) <= now) {
+            match self.schedule.remove(0).change {
+                ParamChange::Bpm(bpm) => self.set_bpm(bpm),
+                ParamChange::MasterCut(hz) => self.set_master_cut(hz),
+                ParamChange::Drift(cents) => self.analog_drift = cents.max(0.0),
+            }
+        }
+    }
+
+    /// Glide from `from` to `to` BPM, moving at each bar line and arriving
+    /// after `bars` bars.
+    pub fn start_ramp(&mut self, from: f32, to: f32, bars: usize) {
+        self.set_bpm(from);
+        self.ramp = Some(TempoRamp { from, to, bars: bars.max(1), start_bar: self.bar });
+    }
+
+    fn advance_ramp(&mut self) {
+        let Some(ramp) = self.ramp.clone() else { return };
+        let elapsed = self.bar.saturating_sub(ramp.start_bar).min(ramp.bars);
+        let t = elapsed as f32 / ramp.bars as f32;
+        self.set_bpm(ramp.from + (ramp.to - ramp.from) * t);
+        if elapsed >= ramp.bars { self.ramp = None; }
+    }
+
+    /// Store every track's current steps as section `name`, replacing any
+    /// section of that name.
+    pub fn save_section(&mut self, name: &str) {
+        self.sections = self.sections_with(name);
+    }
+
+    /// The section list as it would be after `save_section(name)`.
+    pub fn sections_with(&self, name: &str) -> Vec<Section> {
+        let parts = self.tracks.iter()
+            .map(|t| (t.name.clone(), SectionPart::from_track(t)))
+            .collect();
+        let section = Section { name: name.to_string(), parts };
+        let mut sections = self.sections.clone();
+        match sections.iter_mut().find(|s| s.name == name) {
+            Some(existing) => *existing = section,
+            None => sections.push(section),
+        }
+        sections
+    }
+
+    /// `set_arrangement` without freeing: `arrangement` comes back holding
+    /// the one it replaced.
+    pub fn swap_arrangement(&mut self, arrangement: &mut Arrangement) {
+        std::mem::swap(&mut self.arrangement, arrangement);
+        let incoming = std::mem::take(&mut self.arrangement);
+        self.set_arrangement(incoming);
+    }
+
+    /// Play `arrangement` from the next bar line (or from the top for a
+    /// fresh sequencer); an empty one goes back to plain looping and
+    /// leaves the current patterns on the tracks.
+    pub fn set_arrangement(&mut self, arrangement: Arrangement) {
+        self.arrangement = arrangement;
+        self.playing_entry = None;
+        if self.arrangement.total_bars() == 0 {
+            self.song_start = None;
+        } else if self.bar == 0 && self.bar_step == 0 && self.sample_counter == 0 {
+            self.song_start = Some(0);
+            self.steps.iter_mut().for_each(|n| *n = 0);
+            self.advance_arrangement();
+        } else {
+            self.song_start = Some(self.bar + 1);
+        }
+    }
+
+    /// Steps in one pass through the song, if one is playing.
+    pub fn song_steps(&self) -> Option<usize> {
+        self.song_start?;
+        Some(self.arrangement.total_bars() * self.steps_per_bar())
+    }
+
+    fn advance_arrangement(&mut self) {
+        let Some(start) = self.song_start else { return };
+        if self.bar < start { return; }
+        let entry = self.arrangement.entry_at(self.bar - start);
+        if entry == self.playing_entry { return; }
+        self.playing_entry = entry;
+        let Some(section) = entry
+            .and_then(|i| self.arrangement.sections.get(i))
+            .and_then(|(name, _)| self.sections.iter().find(|s| &s.name == name))
+        else { return };
+        // swap steps in place: voices keep ringing and nothing is rebuilt
+        let rest = SectionPart { pattern: vec![REST], ..SectionPart::default() };
+        for (idx, track) in self.tracks.iter_mut().enumerate() {
+            section.parts.get(&track.name).unwrap_or(&rest).apply_to(track);
+            if let Some(voices) = self.voices.get_mut(idx)
+                && voices.len() < track.voice_count()
+            {
+                voices.resize_with(track.voice_count(), Voice::new);
+            }
+        }
+        // every section starts from the top of its patterns
+        self.steps.iter_mut().for_each(|n| *n = 0);
+    }
+
+    /// Steps in one time-signature beat (an 8th in 7/8, a quarter in 4/4)
+    pub fn steps_per_sig_beat(&self) -> usize {
+        (self.steps_per_beat * 4 / self.time_sig_den.max(1)).max(1)
+    }
+
+    /// Whether a beat of `den` (8 for 7/8) is a whole number of steps at
+    /// `steps_per_beat` steps per quarter note.
+    pub fn fits_time_signature(steps_per_beat: usize, den: usize) -> bool {
+        den > 0 && (steps_per_beat * 4).is_multiple_of(den)
+    }
+
+    pub fn steps_per_bar(&self) -> usize { (self.time_sig_num * self.steps_per_sig_beat()).max(1) }
+
+    pub fn is_beat_start(&self) -> bool { self.bar_step.is_multiple_of(self.steps_per_sig_beat()) }
+
+    pub fn is_bar_start(&self) -> bool { self.bar_step == 0 }
+
+    /// Set the time signature, e.g. (7, 8). The denominator picks how many
+    /// steps make a beat; one that doesn't split into whole steps at the
+    /// current resolution (x/32 at 4 steps per beat) is refused, returning
+    /// false with nothing changed.
+    pub fn set_time_signature(&mut self, num: usize, den: usize) -> bool {
+        if num == 0 || !Self::fits_time_signature(self.steps_per_beat, den) { return false; }
+        self.time_sig_num = num;
+        self.time_sig_den = den;
+        self.bar_step %= self.steps_per_bar();
+        true
+    }
+
+    /// Change the step resolution, keeping `bpm`. Patterns still advance one
+    /// entry per step, so a pattern's length in beats is its length divided
+    /// by this; the loop (`get_max_pattern_len` steps) shrinks or stretches
+    /// in time accordingly. A resolution the time signature's beat doesn't
+    /// divide into (3 steps per beat in 7/8) is refused, returning false.
+    pub fn set_steps_per_beat(&mut self, steps: usize) -> bool {
+        let steps = steps.clamp(1, MAX_STEPS_PER_BEAT);
+        if !Self::fits_time_signature(steps, self.time_sig_den) { return false; }
+        self.steps_per_beat = steps;
+        self.samples_per_step = samples_per_step_for(self.sample_rate, self.bpm, self.steps_per_beat).max(1);
+        self.bar_step %= self.steps_per_bar();
+        true
+    }
+
+    /// Silence everything right away (with a click-free fade) without
+    /// touching the tracks; the pattern carries on from the next step.
+    pub fn panic(&mut self) {
+        for v in self.voices.iter_mut().flatten() {
+            v.kill();
+        }
+        self.audition.voice.kill();
+        self.jam.voice.kill();
+        for st in &mut self.stutters {
+            st.remaining = 0;
+        }
+    }
+
+    /// Repeat the last `steps` steps of a track's output for one bar.
+    pub fn stutter(&mut self, track_idx: usize, steps: usize) {
+        let len = steps * self.samples_per_step;
+        let duration = self.steps_per_bar() * self.samples_per_step;
+        if let Some(st) = self.stutters.get_mut(track_idx) {
+            st.trigger(len, duration);
+        }
+    }
+
+    /// Absolute semitones of every note step across all tracks.
+    pub fn used_notes(&self) -> Vec<i32> {
+        if self.scale.is_empty() { return Vec::new(); }
+        self.tracks.iter().flat_map(|t| {
+            (0..t.pattern.len()).filter(|&i| is_note(t.pattern[i]))
+                .flat_map(move |i| t.step_chord(i))
+                .map(move |n| degree_to_semitone(&self.scale, n) + t.transpose)
+        }).collect()
+    }
+
+    /// Set mode and root; an unknown mode falls back to minor, so callers
+    /// check `scale_intervals` first to report it.
+    pub fn set_scale(&mut self, mode: &str, root: &str) {
+        let mode = if scale_intervals(mode).is_some() { mode } else { "minor" };
+        self.scale = scale_from_name(mode, root).unwrap_or_default();
+        self.scale_name = Some((mode.to_lowercase(), root.to_lowercase()));
+    }
+
+    /// Take a scale built elsewhere; `scale` and `name` come back holding
+    /// the old ones.
+    pub fn swap_scale(&mut self, scale: &mut Vec<i32>, name: &mut Option<(String, String)>) {
+        std::mem::swap(&mut self.scale, scale);
+        std::mem::swap(&mut self.scale_name, name);
+    }
+
+    /// Switch to another mode on the same root. Patterns are degrees, so
+    /// every track follows into the new mode.
+    pub fn set_mode(&mut self, mode: &str) {
+        let root = match &self.scale_name {
+            Some((_, root)) => root.clone(),
+            None => semitone_to_note(self.scale.first().copied().unwrap_or(0)).to_lowercase(),
+        };
+        self.set_scale(mode, &root);
+    }
+
+    /// Move the scale to a new root (same scale type) and play it once,
+    /// ascending, so the new key can be heard.
+    pub fn set_root(&mut self, root: &str) {
+        self.scale = rebuild_scale(&self.scale, note_to_semitone(root));
+        if let Some((_, r)) = &mut self.scale_name {
+            *r = root.to_lowercase();
+        }
+        self.audition.play(self.scale_run(), self.samples_per_step);
+    }
+
+    /// The scale once up from middle C's octave, ending on the root above.
+    pub fn scale_run(&self) -> Vec<f32> {
+        let mut run: Vec<f32> = self.scale.iter().map(|n| midi_to_freq(n + 60)).collect();
+        if let Some(first) = self.scale.first() {
+            run.push(midi_to_freq(first + 72));
+        }
+        run
+    }
+
+    /// Set the voice frequency bounds, kept ordered and below Nyquist.
+    pub fn set_freq_clamp(&mut self, min: f32, max: f32) {
+        let nyquist = self.sample_rate / 2.0;
+        self.freq_min = min.clamp(1.0, nyquist);
+        self.freq_max = max.clamp(self.freq_min, nyquist);
+    }
+
+    /// Set the master high-cut; 0 turns it off.
+    pub fn set_master_cut(&mut self, hz: f32) {
+        self.master_cut = hz.max(0.0);
+        if self.master_cut > 0.0 {
+            // Butterworth Q, no resonant bump
+            for f in &mut self.master_filter {
+                f.set_lowpass(self.master_cut, std::f32::consts::FRAC_1_SQRT_2, self.sample_rate);
+            }
+        }
+    }
+
+    /// Replace the track with the same name, or add it. Returns true if replaced.
+    /// Mute and solo carry over, so re-entering a track line keeps them.
+    pub fn upsert_track(&mut self, track: Track) -> bool {
+        self.upsert_parts(TrackParts::new(track, self.sample_rate)).is_some()
+    }
+
+    /// `upsert_track` with the track built ahead of time. A replaced track
+    /// comes back along with whatever parts it didn't need (the held notes
+    /// and effect tails stay); None means the track was added.
+    pub fn upsert_parts(&mut self, mut parts: TrackParts) -> Option<TrackParts> {
+        let Some(idx) = self.tracks.iter().position(|t| t.name == parts.track.name) else {
+            self.add_parts(parts);
+            return None;
+        };
+        let existing = &mut self.tracks[idx];
+        parts.track.muted = existing.muted;
+        parts.track.soloed = existing.soloed;
+        std::mem::swap(existing, &mut parts.track);
+        if let Some(voices) = self.voices.get_mut(idx) {
+            // grow the pool for wider chords; never shrink under a sounding note
+            if voices.len() < parts.voices.len() {
+                parts.voices[..voices.len()].clone_from_slice(voices);
+                std::mem::swap(voices, &mut parts.voices);
+            }
+            // a new shape takes over held notes now, smoothed by the declick
+            let waveform = self.tracks[idx].waveform;
+            voices.iter_mut().for_each(|v| v.set_waveform(waveform));
+        }
+        Some(parts)
+    }
+
+    /// The next `frames` stereo frames, interleaved left/right as a WAV
+    /// stores them.
+    pub fn process_block(&mut self, frames: usize) -> Vec<f32> {
+        (0..frames).flat_map(|_| { let (l, r) = self.process(); [l, r] }).collect()
+    }
+
+    /// Next stereo frame (left, right).
+    pub fn process(&mut self) -> (f32, f32) {
+        self.sample_counter += 1;
+        // >= so a step that just got shorter (swing/tempo change) still ends
+        if self.sample_counter >= self.current_step_len() {
+            self.sample_counter = 0;
+            for (n, track) in self.steps.iter_mut().zip(&self.tracks) {
+                *n = (*n + 1) % track.pattern.len().max(1);
+            }
+            self.bar_step = (self.bar_step + 1) % self.steps_per_bar();
+            if self.bar_step == 0 {
+                self.bar += 1;
+                self.advance_ramp();
+                self.advance_arrangement();
+            }
+            self.apply_due_changes();
+            if self.metronome && self.is_beat_start() {
+                self.click.trigger(self.is_bar_start());
+            }
+        }
+        self.release_gated_notes();
+        self.trigger_step();
+
+        if self.sample_counter.is_multiple_of(DRIFT_INTERVAL) {
+            self.update_drift();
+        }
+
+        // mix all tracks: a plain sum, so adding a track never turns the
+        // others down; the master bus keeps the total in range
+
+        let meter_coeff = 1.0 - (-1.0 / (METER_TIME * self.sample_rate)).exp();
+        let any_solo = self.tracks.iter().any(|t| t.soloed);
+        let (mut left, mut right) = (0.0, 0.0);
+        for (track_idx, voices) in self.voices.iter_mut().enumerate() {
+            let lfo = self.tracks.get(track_idx).and_then(|t| t.lfo);
+            let lfo_out = match (lfo, self.lfo_phases.get_mut(track_idx)) {
+                (Some(mut lfo), Some(phase)) => {
+                    lfo.phase = *phase;
+                    let v = lfo.value(self.sample_rate);
+                    *phase = lfo.phase;
+                    Some((lfo.target, v))
+                }
+                _ => None,
+            };
+            let pitch_mod = match lfo_out {
+                Some((LfoTarget::Pitch, v)) => v,
+                _ => 0.0,
+            };
+            // `side` is the unison spread: voices weighted by their stereo
+            // position. It follows the track's filter, gain and gate, and
+            // drops out while stuttering (the repeat buffer is mono).
+            let mut track_sum = 0.0;
+            let mut side = 0.0;
+            for v in voices {
+                v.pitch_mod = pitch_mod;
+                let x = v.process(self.sample_rate);
+                track_sum += x;
+                side += x * v.unison_pos;
+            }
+            let gain = self.tracks.get(track_idx).map_or(1.0, |t| {
+                if t.muted || (any_solo && !t.soloed) { 0.0 } else { t.gain }
+            });
+            let width = self.tracks.get(track_idx).map_or(0.0, |t| t.unison_width.clamp(0.0, 1.0));
+            let mut out = track_sum * gain;
+            side *= gain * width * UNISON_MAX_SIDE;
+            if let Some(track) = self.tracks.get(track_idx)
+                && track.is_crushed()
+                && let Some(crush) = self.crushers.get_mut(track_idx)
+            {
+                crush.set(track.crush_bits, track.crush_downsample);
+                out = crush.process(out);
+            }
+            if let Some(track) = self.tracks.get(track_idx)
+                && let Some(filter) = self.filters.get_mut(track_idx)
+            {
+                let cutoff = match lfo_out {
+                    Some((LfoTarget::Cutoff, octaves)) => track.filter_cutoff * 2f32.powf(octaves),
+                    _ => track.filter_cutoff,
+                };
+                filter.set(cutoff, track.filter_resonance);
+                out = filter.process(out);
+                if let Some(side_filter) = self.side_filters.get_mut(track_idx) {
+                    side_filter.set(cutoff, track.filter_resonance);
+                    side = side_filter.process(side);
+                }
+            }
+            if self.tracks.get(track_idx).is_some_and(|t| t.phase_invert) {
+                out = -out;
+                side = -side;
+            }
+            if let Some(track) = self.tracks.get(track_idx)
+                && let Some(threshold) = track.gate_threshold
+                && let Some(gate) = self.gates.get_mut(track_idx)
+            {
+                out = gate.process(out, threshold, track.gate_release, self.sample_rate);
+                side *= gate.gain;
+            }
+            if let Some(st) = self.stutters.get_mut(track_idx) {
+                out = st.process(out);
+                if st.is_active() { side = 0.0; }
+            }
+            if let Some(track) = self.tracks.get(track_idx)
+                && track.delay_mix > 0.0
+                && let Some(delay) = self.delays.get_mut(track_idx)
+            {
+                delay.set(track.delay_time, track.delay_feedback, track.delay_mix);
+                out = delay.process(out);
+            }
+            if let Some(meter) = self.meters.get_mut(track_idx) {
+                meter.update(out, meter_coeff);
+            }
+            let (pan_l, pan_r) = self.tracks.get(track_idx).map_or((1.0, 1.0), Track::pan_gains);
+            left += out * pan_l - side;
+            right += out * pan_r + side;
+        }
+        let audition = (self.audition.process(self.sample_rate) + self.jam.process(self.sample_rate)) * FRAC_1_SQRT_2;
+        left += audition;
+        right += audition;
+        if self.reverb[0].wet > 0.0 {
+            left = self.reverb[0].process(left);
+            right = self.reverb[1].process(right);
+        }
+        if self.master_cut > 0.0 {
+            left = self.master_filter[0].process(left);
+            right = self.master_filter[1].process(right);
+        }
+        // the click sits on top at a fixed level, whatever the mix does
+        let click = self.click.process(self.sample_rate);
+        (self.master.process(left) + click, self.master.process(right) + click)
+    }
+
+    /// Leaky random walk per voice, scaled to `analog_drift` cents.
+    fn update_drift(&mut self) {
+        let amount = self.analog_drift.max(0.0);
+        for voices in &mut self.voices {
+            for v in voices {
+                if amount == 0.0 {
+                    v.drift_walk = 0.0;
+                    v.drift_cents = 0.0;
+                    continue;
+                }
+                v.drift_walk += self.rng.bipolar() * DRIFT_STEP - v.drift_walk * DRIFT_LEAK;
+                v.drift_walk = v.drift_walk.clamp(-1.0, 1.0);
+                v.drift_cents = v.drift_walk * amount;
+            }
+        }
+    }
+
+    /// Swap in a whole track list (undo/redo), rebuilding the voices and
+    /// other per-track state to match.
+    pub fn restore_tracks(&mut self, tracks: Vec<Track>) {
+        self.clear_tracks();
+        for track in tracks {
+            self.add_track(track);
+        }
+    }
+
+    /// Change tempo without moving the playhead. The step in progress just
+    /// ends at the new length (or at once, if already past it).
+    pub fn set_bpm(&mut self, bpm: f32) {
+        if bpm <= 0.0 { return; }
+        self.bpm = bpm;
+        self.samples_per_step = samples_per_step_for(self.sample_rate, bpm, self.steps_per_beat).max(1);
+    }
+
+    /// Length in samples of the step now playing: with swing, on-beat steps
+    /// stretch and off-beats shrink by the same amount.
+    pub fn current_step_len(&self) -> usize {
+        let shift = (self.swing * self.samples_per_step as f32) as usize;
+        if self.bar_step.is_multiple_of(2) {
+            self.samples_per_step + shift
+        } else {
+            self.samples_per_step.saturating_sub(shift).max(1)
+        }
+    }
+
+    pub fn set_swing(&mut self, amount: f32) {
+        self.swing = amount.clamp(0.0, MAX_SWING);
+    }
+
+    /// Voices still sounding (attack through the end of the release).
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().flatten()
+            .filter(|v| !v.is_finished() && !v.is_silent())
+            .count()
+    }
+
+    pub fn set_reverb(&mut self, room_size: f32, damping: f32, wet: f32) {
+        self.reverb.iter_mut().for_each(|r| r.set(room_size, damping, wet));
+    }
+
+    /// Run at a new sample rate: step length, filters and every buffer
+    /// sized in seconds are rebuilt for it. Effect tails are dropped.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let n = self.tracks.len();
+        self.sample_rate = sample_rate;
+        self.samples_per_step = samples_per_step_for(sample_rate, self.bpm, self.steps_per_beat).max(1);
+        self.filters = vec![Filter::new(sample_rate); n];
+        self.side_filters = vec![Filter::new(sample_rate); n];
+        self.stutters = vec![Stutter::new(sample_rate); n];
+        self.delays = vec![Delay::new(sample_rate); n];
+        let (room, damping, wet) = (self.reverb[0].room_size, self.reverb[0].damping, self.reverb[0].wet);
+        self.reverb = [Reverb::new(sample_rate, 0), Reverb::new(sample_rate, REVERB_STEREO_SPREAD)];
+        self.set_reverb(room, damping, wet);
+        self.set_master_cut(self.master_cut);
+        self.freq_max = self.freq_max.min(sample_rate / 2.0);
+        self.sample_counter = 0;
+    }
+
+    /// Restart every random source from `seed`.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+        for (t, voices) in self.voices.iter_mut().enumerate() {
+            for (i, v) in voices.iter_mut().enumerate() {
+                v.seed_noise(noise_seed(seed, t, i));
+            }
+        }
+    }
+
+    /// Back to the top of the pattern with silent voices, triggering the
+    /// first step straight away (used for offline renders).
+    pub fn rewind(&mut self) {
+        self.steps.iter_mut().for_each(|n| *n = 0);
+        self.sample_counter = 0;
+        self.bar_step = 0;
+        self.bar = 0;
+        for voices in &mut self.voices {
+            voices.iter_mut().for_each(|v| *v = Voice::new());
+        }
+        self.reseed(self.seed);
+        self.arp_steps.iter_mut().for_each(|n| *n = 0);
+        self.note_offs.iter_mut().for_each(|n| *n = None);
+        self.human_delays.iter_mut().for_each(|n| *n = 0);
+        self.click = Click::default();
+        if self.song_start.is_some() {
+            self.song_start = Some(0);
+            self.playing_entry = None;
+            self.advance_arrangement();
+        }
+        self.trigger_step();
+    }
+
+    /// Steps in the longest pattern, which renders count as one loop.
+    /// Counted in steps, not beats, so it doesn't change with
+    /// `steps_per_beat`.
+    pub fn get_max_pattern_len(&self) -> usize {
+        self.tracks.iter().map(|t| t.pattern.len()).max().unwrap_or(1).max(1)
+    }
+
+    /// Pick how late each humanized track plays the step now starting.
+    /// Only humanized tracks roll, so plain patterns leave the RNG alone.
+    /// Notes can only land late: a step can't fire before it begins.
+    fn draw_human_delays(&mut self, step_len: usize) {
+        let max = HUMANIZE_MAX_SECS * self.sample_rate;
+        for (delay, track) in self.human_delays.iter_mut().zip(&self.tracks) {
+            *delay = if track.humanize > 0.0 {
+                ((self.rng.next_f32() * track.humanize.min(1.0) * max) as usize).min(step_len / 2)
+            } else {
+                0
+            };
+        }
+    }
+
+    /// Count down each track's gate and release its notes when it expires.
+    fn release_gated_notes(&mut self) {
+        for (off, voices) in self.note_offs.iter_mut().zip(&mut self.voices) {
+            match off {
+                Some(0) => {
+                    voices.iter_mut().for_each(Voice::note_off);
+                    *off = None;
+                }
+                Some(n) => *n -= 1,
+                None => {}
+            }
+        }
+    }
+
+    /// Trigger every track whose step (including its timing offset) starts
+    /// on the current sample.
+    fn trigger_step(&mut self) {
+        let accent = if self.is_beat_start() {
+            1.0 + self.auto_accent.max(0.0)
+        } else {
+            1.0
+        };
+        let bar_pos = self.bar_step as f32 / self.steps_per_bar() as f32;
+        let step_len = self.current_step_len();
+        if self.sample_counter == 0 {
+            self.draw_human_delays(step_len);
+        }
+        for (track_idx, track) in self.tracks.iter().enumerate() {
+            if track.pattern.is_empty() { continue; }
+
+            let idx = self.steps.get(track_idx).copied().unwrap_or(0) % track.pattern.len();
+            let human = self.human_delays.get(track_idx).copied().unwrap_or(0);
+            let due = (track.step_delay(idx, step_len) + human).min(step_len.saturating_sub(1));
+            if due != self.sample_counter { continue; }
+            
+            // a tie keeps the previous note sounding; under an arp it keeps
+            // walking the chord that note struck
+            let src = match (track.pattern[idx], track.arp) {
+                (TIE, None) => continue,
+                (TIE, Some(_)) => match track.held_step(idx) {
+                    Some(held) => held,
+                    None => continue,
+                },
+                _ => idx,
+            };
+            let note = track.pattern[src];
+            // a step that loses its roll behaves like a rest; only roll for
+            // steps that can fail so plain patterns leave the RNG alone
+            let prob = if src == idx { track.step_probability(idx) } else { 1.0 };
+            let skipped = is_note(note) && prob < 1.0 && self.rng.next_f32() >= prob;
+            if !is_note(note) || skipped {
+                // rest: let the previous note ring out
+                if let Some(voices) = self.voices.get_mut(track_idx) {
+                    voices.iter_mut().for_each(Voice::note_off);
+                }
+                continue;
+            }
+
+            let (chord, span) = match (track.arp, self.arp_steps.get_mut(track_idx)) {
+                (Some(arp), Some(count)) => {
+                    let per_note = arp.steps_per_note(self.steps_per_beat);
+                    let pos = *count;
+                    *count += 1;
+                    if !pos.is_multiple_of(per_note) { continue; }
+                    let chord = track.step_chord(src);
+                    (vec![chord[arp.note_index(pos / per_note, chord.len(), &mut self.rng)]], per_note)
+                }
+                _ => (track.step_chord(src), track.note_span(src)),
+            };
+            if let Some(off) = self.note_offs.get_mut(track_idx) {
+                let gate = track.gate.clamp(0.0, 1.0);
+                *off = (gate < 1.0).then_some(((span as f32 - 1.0 + gate) * step_len as f32) as usize);
+            }
+            
+            let mut velocity = accent * track.velocity_curve.gain(bar_pos) * track.step_velocity(src);
+            if track.humanize > 0.0 {
+                velocity *= 1.0 + track.humanize.min(1.0) * HUMANIZE_VELOCITY * self.rng.bipolar();
+            }
+
+            if let Some(voices) = self.voices.get_mut(track_idx) {
+                let notes = track.stack_notes(&chord, &self.scale, voices.len());
+                let count = voices.len();
+                for (i, (v, &midi)) in voices.iter_mut().zip(&notes).enumerate() {
+                    v.unison_pos = Track::unison_position(i, count);
+                    v.detune_cents = track.detune_cents * v.unison_pos;
+                    let freq = midi_to_freq(midi + self.transpose_all).clamp(self.freq_min, self.freq_max);
+                    // with glide, a note still sounding slides to the new
+                    // pitch without retriggering (a 303-style slide)
+                    let slide = track.glide > 0.0 && v.is_held();
+                    if slide {
+                        v.glide_to(freq, track.glide);
+                    } else {
+                        v.set_frequency(freq);
+                    }
+                    v.declick_time = self.declick;
+                    v.freq_min = self.freq_min;
+                    v.freq_max = self.freq_max;
+                    track.configure_voice(v);
+                    v.set_velocity(velocity, track.vel_to_env);
+                    if slide { continue; }
+                    // on a voice still ringing, the declick smooths the jump
+                    // back to phase 0 (loudest on saw and square)
+                    if track.retrig_phase { v.reset_phase(); }
+                    v.reset_env();
+                }
+            }
+        }
+    }
+
+    pub fn to_project(&self) -> ProjectData {
+        let (mode, root) = self.scale_name.clone().unzip();
+        ProjectData {
+            tracks: self.tracks.clone(),
+            scale: self.scale.clone(),
+            bpm: self.bpm,
+            mode,
+            root,
+            transpose_all: self.transpose_all,
+            swing: self.swing,
+            steps_per_beat: self.steps_per_beat,
+            time_sig_num: self.time_sig_num,
+            time_sig_den: self.time_sig_den,
+            master_gain: self.master.gain,
+            seed: self.seed,
+            reverb_room: self.reverb[0].room_size,
+            reverb_damping: self.reverb[0].damping,
+            reverb_wet: self.reverb[0].wet,
+            sections: self.sections.clone(),
+            arrangement: self.arrangement.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 44100.0;
+
+    fn approx(a: f32, b: f32, tol: f32) -> bool { (a - b).abs() <= tol }
+
+    /// One track playing `pattern` at 120 BPM, rewound and ready.
+    fn seq_with(pattern: Vec<i32>) -> Sequencer {
+        let mut seq = Sequencer::new(SR);
+        seq.tracks[0].pattern = pattern;
+        seq.set_bpm(120.0);
+        seq.rewind();
+        seq
+    }
+
+    /// Run whole steps so the sequencer sits on the first sample of the next one.
+    fn run_steps(seq: &mut Sequencer, steps: usize) {
+        for _ in 0..steps * seq.samples_per_step { seq.process(); }
+    }
+
+    /// A held sine on track 0, retriggered by every note in `pattern`.
+    fn sine_seq(pattern: Vec<i32>) -> Sequencer {
+        let mut seq = seq_with(pattern);
+        let track = &mut seq.tracks[0];
+        track.waveform = Waveform::Sine;
+        track.attack = 0.0;
+        track.decay = 0.0;
+        track.sustain = 1.0;
+        track.release = 0.0;
+        seq.rewind();
+        seq
+    }
+
+    /// Loudest left-channel sample in each of the next `steps` steps.
+    fn step_peaks(seq: &mut Sequencer, steps: usize) -> Vec<f32> {
+        (0..steps).map(|_| {
+            (0..seq.samples_per_step).map(|_| {
+                let x = seq.process().0.abs();
+                // the window's last sample already starts the next step
+                if seq.sample_counter == 0 { 0.0 } else { x }
+            }).fold(0.0, f32::max)
+        }).collect()
+    }
+
+    /// RMS of the left channel over the next `steps` steps.
+    fn rms_over(seq: &mut Sequencer, steps: usize) -> f32 {
+        let n = steps * seq.samples_per_step;
+        ((0..n).map(|_| seq.process().0.powi(2)).sum::<f32>() / n as f32).sqrt()
+    }
+
+    #[test]
+    fn midi_to_freq_follows_equal_temperament() {
+        assert!(approx(midi_to_freq(69), 440.0, 1e-3));
+        assert!(approx(midi_to_freq(81), 880.0, 1e-3));
+        assert!(approx(midi_to_freq(57), 220.0, 1e-3));
+        assert!(approx(midi_to_freq(60), 261.626, 1e-2));
+    }
+
+    #[test]
+    fn note_names_map_to_semitones() {
+        assert_eq!(note_to_semitone("c"), 0);
+        assert_eq!(note_to_semitone("C#"), 1);
+        assert_eq!(note_to_semitone("db"), 1);
+        assert_eq!(note_to_semitone("a"), 9);
+        assert_eq!(note_to_semitone("b"), 11);
+        // unknown names fall back to C
+        assert_eq!(note_to_semitone("h"), 0);
+    }
+
+    #[test]
+    fn minor_scale_starts_on_its_root() {
+        assert_eq!(minor_scale("c"), vec![0, 2, 3, 5, 7, 8, 10]);
+        assert_eq!(minor_scale("a"), vec![9, 11, 12, 14, 16, 17, 19]);
+    }
+
+    #[test]
+    fn degrees_past_the_scale_climb_an_octave() {
+        let scale = minor_scale("c");
+        assert_eq!(degree_to_semitone(&scale, 0), 0);
+        assert_eq!(degree_to_semitone(&scale, 7), 12);
+        assert_eq!(degree_to_semitone(&scale, 9), 15);
+    }
+
+    #[test]
+    fn step_length_follows_bpm_and_resolution() {
+        // 120 BPM 16ths: a quarter is half a second, a step an eighth of one
+        assert_eq!(samples_per_step_for(SR, 120.0, 4), 5512);
+        assert_eq!(samples_per_step_for(48000.0, 120.0, 4), 6000);
+        assert_eq!(samples_per_step_for(48000.0, 60.0, 4), 12000);
+        // triplets: three steps per quarter
+        assert_eq!(samples_per_step_for(48000.0, 120.0, 3), 8000);
+
+        let mut seq = Sequencer::new(48000.0);
+        seq.set_bpm(90.0);
+        assert_eq!(seq.samples_per_step, 8000);
+        seq.set_bpm(0.0);
+        assert_eq!(seq.bpm, 90.0, "a zero tempo is ignored");
+    }
+
+    #[test]
+    fn sine_voice_produces_the_expected_samples() {
+        let mut track = Track::new("sine");
+        track.waveform = Waveform::Sine;
+        track.attack = 0.0;
+        track.decay = 0.0;
+        track.sustain = 1.0;
+        let mut v = Voice::new();
+        track.configure_voice(&mut v);
+        v.set_frequency(1000.0);
+        v.reset_env();
+
+        let samples: Vec<f32> = (0..200).map(|_| v.process(SR)).collect();
+        // past the shortest possible attack the voice is a plain sine
+        for (n, &x) in samples.iter().enumerate().skip(10) {
+            let expected = (2.0 * PI * 1000.0 * n as f32 / SR).sin() * v.amp;
+            assert!(approx(x, expected, 1e-3), "sample {n}: {x} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn voice_releases_to_silence_after_note_off() {
+        let mut v = Voice::new();
+        v.set_adsr(0.0, 0.0, 1.0, 0.01);
+        v.reset_env();
+        for _ in 0..100 { v.process(SR); }
+        assert!(v.is_held());
+        v.note_off();
+        assert!(!v.is_held());
+        for _ in 0..(0.01 * SR) as usize + 1 { v.process(SR); }
+        assert!(v.is_finished());
+        assert_eq!(v.process(SR), 0.0);
+    }
+
+    #[test]
+    fn waveform_switch_mid_note_is_declicked() {
+        let max_step = |declick: f32| {
+            let mut v = Voice::new();
+            v.declick_time = declick;
+            v.set_adsr(0.0, 0.0, 1.0, 0.1);
+            v.set_frequency(100.0);
+            v.set_waveform(Waveform::Square);
+            v.reset_env();
+            // into the high half of the square, then switch to a saw (low there)
+            let mut last = 0.0;
+            for _ in 0..100 { last = v.process(SR); }
+            v.set_waveform(Waveform::Saw);
+            (0..200).map(|_| { let x = v.process(SR); let d = (x - last).abs(); last = x; d })
+                .fold(0.0, f32::max)
+        };
+        assert!(max_step(0.0) > 0.2, "without declick the switch jumps");
+        assert!(max_step(DEFAULT_DECLICK) < 0.01);
+    }
+
+    #[test]
+    fn retrig_restarts_the_oscillator_on_each_note() {
+        // every unison voice of the track, detuned and stacked apart
+        let phases_after = |retrig: bool| {
+            let mut seq = seq_with(vec![0, 0]);
+            seq.tracks[0].retrig_phase = retrig;
+            seq.tracks[0].detune_cents = 12.0;
+            seq.tracks[0].voice_spread = 7;
+            seq.rewind();
+            run_steps(&mut seq, 1);
+            seq.voices[0].iter().map(|v| v.phase).collect::<Vec<f32>>()
+        };
+        // one sample into the new step
+        let synced = phases_after(true);
+        assert!(synced.len() > 1);
+        assert!(synced.iter().all(|&p| p < 0.01), "all voices restart together: {synced:?}");
+        let free = phases_after(false);
+        assert!(free.iter().any(|&p| p > 0.1), "free-running oscillators carry on: {free:?}");
+
+        assert!(Track::new("t").retrig_phase, "retriggering is the default");
+        let saved = |json: &str| serde_json::from_str::<Track>(&format!(r#"{{"name":"t","pattern":[0],"octave":3,"transpose":0,"waveform":"Saw","voice_spread":7{json}}}"#)).unwrap();
+        assert!(saved("").retrig_phase);
+        assert!(!saved(r#","phase_sync":false"#).retrig_phase, "old projects keep their setting");
+        assert!(saved(r#","phase_sync":true"#).retrig_phase);
+        assert!(!saved(r#","retrig_phase":false"#).retrig_phase);
+    }
+
+    #[test]
+    fn retriggered_notes_stay_click_free() {
+        // largest sample-to-sample jump at the step boundaries and inside the notes
+        let jumps = |declick: f32| {
+            // retrig_phase left at its default
+            let mut seq = sine_seq(vec![0]);
+            seq.tracks[0].octave = 4;
+            seq.declick = declick;
+            let (mut edge, mut inside, mut last) = (0.0f32, 0.0f32, seq.process().0);
+            for _ in 1..8 * seq.samples_per_step {
+                let x = seq.process().0;
+                let jump = (x - last).abs();
+                if seq.sample_counter < 2 { edge = edge.max(jump); } else { inside = inside.max(jump); }
+                last = x;
+            }
+            (edge, inside)
+        };
+        let (edge, inside) = jumps(0.0);
+        assert!(edge > inside * 2.0, "resetting the phase jumps: {edge} vs {inside}");
+        let (edge, inside) = jumps(DEFAULT_DECLICK);
+        assert!(edge <= inside * 1.2, "the declick bridges the reset: {edge} vs {inside}");
+    }
+
+    #[test]
+    fn patterns_wrap_at_their_own_length() {
+        let mut seq = seq_with(vec![0, 1, 2]);
+        let mut positions = vec![seq.steps[0]];
+        for _ in 0..6 {
+            run_steps(&mut seq, 1);
+            positions.push(seq.steps[0]);
+        }
+        assert_eq!(positions, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn patterns_of_different_lengths_run_as_a_polymeter() {
+        let mut seq = seq_with(vec![0, 1, 2]);
+        seq.add_track(Track::new("four"));
+        seq.tracks[1].pattern = vec![0, 1, 2, 3];
+        seq.rewind();
+        run_steps(&mut seq, 5);
+        assert_eq!(seq.steps, vec![2, 1]);
+        run_steps(&mut seq, 7);
+        assert_eq!(seq.steps, vec![0, 0], "3 against 4 lines up every 12 steps");
+    }
+
+    #[test]
+    fn rests_release_and_notes_retrigger() {
+        let mut seq = seq_with(vec![0, REST, TIE, 4]);
+        assert!(seq.voices[0][0].is_held(), "step 0 plays on rewind");
+        run_steps(&mut seq, 1);
+        assert!(!seq.voices[0][0].is_held(), "a rest releases");
+        run_steps(&mut seq, 1);
+        assert!(!seq.voices[0][0].is_held(), "a tie after a rest has nothing to hold");
+        run_steps(&mut seq, 1);
+        assert!(seq.voices[0][0].is_held());
+    }
+
+    #[test]
+    fn ties_hold_and_gate_shortens_notes() {
+        let mut seq = seq_with(vec![0, TIE, REST, REST]);
+        seq.tracks[0].gate = 0.5;
+        seq.rewind();
+        let step = seq.samples_per_step;
+        for _ in 0..step + step / 4 { seq.process(); }
+        assert!(seq.voices[0][0].is_held(), "the tie carries the note into step 2");
+        for _ in 0..step / 2 { seq.process(); }
+        assert!(!seq.voices[0][0].is_held(), "released halfway through the tied step");
+    }
+
+    #[test]
+    fn humanize_loosens_timing_reproducibly() {
+        let onsets = |humanize: f32| {
+            let mut seq = seq_with(vec![0, 0, 0, 0]);
+            seq.tracks[0].humanize = humanize;
+            seq.tracks[0].gate = 0.5;
+            seq.reseed(3);
+            seq.rewind();
+            let mut out = Vec::new();
+            let mut was_held = false;
+            for n in 0..4 * seq.samples_per_step {
+                let held = seq.voices[0][0].is_held();
+                if held && !was_held { out.push(n % seq.samples_per_step); }
+                was_held = held;
+                seq.process();
+            }
+            out
+        };
+        assert!(onsets(0.0).iter().all(|&n| n == 0));
+        let loose = onsets(1.0);
+        assert!(loose.iter().any(|&n| n > 0));
+        assert!(loose.iter().all(|&n| n as f32 <= HUMANIZE_MAX_SECS * SR));
+        assert_eq!(loose, onsets(1.0), "the same seed gives the same groove");
+    }
+
+    #[test]
+    fn process_block_interleaves_stereo_frames() {
+        let mut seq = seq_with(vec![0, 2, 4]);
+        let block = seq.process_block(256);
+        assert_eq!(block.len(), 512);
+        assert!(block.iter().any(|&x| x != 0.0));
+        assert!(block.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn same_seed_renders_the_same_audio() {
+        let mut a = seq_with(vec![0, 3, REST, 5]);
+        a.tracks[0].waveform = Waveform::Noise;
+        a.tracks[0].probabilities = vec![0.5; 4];
+        a.reseed(7);
+        a.rewind();
+        let mut b = Sequencer::from_project(a.to_project(), SR);
+        b.rewind();
+        let frames = 8 * a.samples_per_step;
+        assert_eq!(a.process_block(frames), b.process_block(frames));
+    }
+
+    #[test]
+    fn arrangement_swaps_patterns_at_bar_lines() {
+        let mut seq = seq_with(vec![0, 1, 2, 3]);
+        seq.save_section("a");
+        seq.tracks[0].pattern = vec![5, 5];
+        seq.save_section("b");
+        seq.rewind();
+        seq.set_arrangement(Arrangement { sections: vec![("a".into(), 1), ("b".into(), 2)] });
+        assert_eq!(seq.tracks[0].pattern, vec![0, 1, 2, 3], "a fresh song starts at once");
+        let bar = seq.steps_per_bar();
+        run_steps(&mut seq, bar);
+        assert_eq!(seq.tracks[0].pattern, vec![5, 5]);
+        run_steps(&mut seq, 2 * bar);
+        assert_eq!(seq.tracks[0].pattern, vec![0, 1, 2, 3], "the song loops");
+
+        // once playback is under way a new song waits for the next bar line
+        seq.set_arrangement(Arrangement { sections: vec![("b".into(), 1)] });
+        assert_eq!(seq.tracks[0].pattern, vec![0, 1, 2, 3]);
+        run_steps(&mut seq, bar);
+        assert_eq!(seq.tracks[0].pattern, vec![5, 5]);
+    }
+
+    #[test]
+    fn arrangement_entries_cover_their_bars() {
+        let song = Arrangement { sections: vec![("intro".into(), 2), ("drop".into(), 3)] };
+        assert_eq!(song.total_bars(), 5);
+        let entries: Vec<_> = (0..6).map(|bar| song.entry_at(bar)).collect();
+        assert_eq!(entries, vec![Some(0), Some(0), Some(1), Some(1), Some(1), Some(0)]);
+        assert_eq!(Arrangement::default().entry_at(3), None);
+    }
+
+    #[test]
+    fn pattern_transforms() {
+        assert_eq!(reverse_pattern(&[0, 1, REST, 3]), vec![3, REST, 1, 0]);
+        assert_eq!(rotate_pattern(&[0, 1, 2, 3], 1), vec![3, 0, 1, 2]);
+        assert_eq!(rotate_pattern(&[0, 1, 2, 3], -1), vec![1, 2, 3, 0]);
+        assert_eq!(invert_pattern(&[0, 2, REST, 7], 7), vec![6, 4, REST, 13]);
+    }
+
+    #[test]
+    fn drift_stays_in_bounds_and_repeats_per_seed() {
+        let drift = |seed: u64| {
+            let mut seq = seq_with(vec![0, 2, 4, 2]);
+            seq.analog_drift = 5.0;
+            seq.reseed(seed);
+            seq.rewind();
+            (0..200).map(|_| {
+                for _ in 0..DRIFT_INTERVAL { seq.process(); }
+                seq.voices[0][0].drift_cents
+            }).collect::<Vec<f32>>()
+        };
+        let cents = drift(42);
+        assert!(cents.iter().all(|c| c.abs() <= 5.0));
+        assert!(cents.iter().any(|&c| c != 0.0), "drift moves the pitch");
+        assert_eq!(cents, drift(42));
+        assert_ne!(cents, drift(43));
+    }
+
+    #[test]
+    fn meters_track_peak_and_rms_per_track() {
+        let coeff = 1.0 - (-1.0 / (METER_TIME * SR)).exp();
+        let mut meter = Meter::default();
+        for n in 0..(2.0 * SR) as usize {
+            meter.update(0.5 * (2.0 * PI * 100.0 * n as f32 / SR).sin(), coeff);
+        }
+        assert!(approx(meter.peak, 0.5, 0.01));
+        assert!(approx(meter.rms(), 0.5 * FRAC_1_SQRT_2, 0.01));
+
+        // in the sequencer each track is metered on its own signal
+        let mut seq = seq_with(vec![0, 2, 4, 2]);
+        seq.add_track(Track::new("quiet"));
+        seq.tracks[1].pattern = vec![0, 2, 4, 2];
+        seq.tracks[1].gain = 0.25;
+        seq.rewind();
+        run_steps(&mut seq, 4);
+        let (loud, quiet) = (seq.meters[0].rms(), seq.meters[1].rms());
+        assert!(loud > 0.0);
+        assert!(approx(quiet / loud, 0.25, 0.02), "ratio {}", quiet / loud);
+    }
+
+    #[test]
+    fn zero_envelope_times_stay_finite() {
+        let mut v = Voice::new();
+        v.set_adsr(0.0, 0.0, 1.0, 0.0);
+        v.set_frequency(440.0);
+        v.reset_env();
+        let on: Vec<f32> = (0..64).map(|_| v.process(SR)).collect();
+        assert!(on.iter().all(|x| x.is_finite()));
+        // instant-on: full level within the minimal attack
+        assert!(approx(v.env_level, 1.0, 1e-6));
+        v.note_off();
+        let off: Vec<f32> = (0..64).map(|_| v.process(SR)).collect();
+        assert!(off.iter().all(|x| x.is_finite()));
+        assert!(v.is_finished());
+
+        let mut v = Voice::new();
+        v.set_adsr(-1.0, -1.0, 2.0, -1.0);
+        v.reset_env();
+        assert!((0..64).map(|_| v.process(SR)).all(f32::is_finite));
+    }
+
+    #[test]
+    fn master_cut_attenuates_above_the_corner() {
+        let rms_with_cut = |hz: f32| {
+            let mut seq = sine_seq(vec![0]);
+            seq.tracks[0].octave = 5;
+            // one unbroken sine; a phase reset each step would splatter
+            seq.tracks[0].retrig_phase = false;
+            seq.set_master_cut(hz);
+            run_steps(&mut seq, 1);
+            rms_over(&mut seq, 4)
+        };
+        let dry = rms_with_cut(0.0);
+        assert!(dry > 0.1);
+        // G4 (392 Hz) sits two octaves above a 100 Hz corner: about -24 dB
+        assert!(rms_with_cut(100.0) < dry * 0.1);
+        assert!(rms_with_cut(5000.0) > dry * 0.9, "well below the corner passes");
+    }
+
+    #[test]
+    fn voice_clamp_holds_after_detune() {
+        let phase_after = |freq_max: f32| {
+            let mut v = Voice::new();
+            v.freq_max = freq_max;
+            v.set_frequency(1000.0);
+            v.detune_cents = 1200.0;
+            v.pitch_mod = 1.0;
+            for _ in 0..10 { v.process(SR); }
+            v.phase
+        };
+        // an octave of detune and a semitone of LFO stop at the ceiling
+        assert!(approx(phase_after(1000.0), 10.0 * 1000.0 / SR, 1e-5));
+        assert!(phase_after(f32::MAX) > 10.0 * 2000.0 / SR);
+    }
+
+    #[test]
+    fn best_fit_scale_finds_the_key() {
+        let c_major = [60, 62, 64, 65, 67, 69, 71, 72];
+        assert_eq!(best_fit_scale(&c_major), ("major".to_string(), "C".to_string()));
+        // same notes, but leaning on A: its relative minor
+        let a_minor = [57, 57, 57, 59, 60, 62, 64, 65, 67, 45];
+        assert_eq!(best_fit_scale(&a_minor), ("minor".to_string(), "A".to_string()));
+        // and leaning on F, the mode with the raised fourth
+        let f_lydian = [65, 67, 69, 71, 72, 74, 76, 65];
+        assert_eq!(best_fit_scale(&f_lydian), ("lydian".to_string(), "F".to_string()));
+    }
+
+    #[test]
+    fn gate_closes_below_its_threshold() {
+        let mut gate = NoiseGate::default();
+        let sine = |n: usize, amp: f32| amp * (2.0 * PI * 220.0 * n as f32 / SR).sin();
+        let loudest = |gate: &mut NoiseGate, amp: f32| {
+            // skip the first 100 ms: five release times
+            (0..SR as usize * 3 / 20)
+                .map(|n| (gate.process(sine(n, amp), -20.0, 0.02, SR) / amp).abs())
+                .skip(SR as usize / 10)
+                .fold(0.0, f32::max)
+        };
+        // -6 dB passes through untouched once open
+        assert!(approx(loudest(&mut gate, 0.5), 1.0, 0.01));
+        // -40 dB fades out: at least 30 dB down after five release times
+        assert!(loudest(&mut gate, 0.01) < db_to_gain(-30.0));
+        assert!(gate.gain < 0.01);
+    }
+
+    #[test]
+    fn harmonizing_below_keeps_every_note_sounding() {
+        let mut src = Track::new("lead");
+        src.pattern = vec![0, 1, REST, TIE, 4];
+        assert_eq!(harmonize_track(&src, 3, "up").pattern, vec![2, 3, REST, TIE, 6]);
+
+        let below = harmonize_track(&src, -3, "down");
+        assert_eq!(below.pattern, vec![-2, -1, REST, TIE, 2]);
+        let mut seq = seq_with(below.pattern);
+        let mut struck = 0;
+        for _ in 0..5 {
+            if seq.voices[0][0].is_held() { struck += 1; }
+            run_steps(&mut seq, 1);
+        }
+        // the rest stops the voice; the tie keeps it off
+        assert_eq!(struck, 3);
+        assert_eq!(seq.used_notes().len(), 3);
+    }
+
+    #[test]
+    fn patterns_save_as_text_and_load_old_numbers() {
+        let mut track = Track::new("t");
+        track.pattern = vec![0, REST, TIE, -1, -2];
+        let json = serde_json::to_string(&track).unwrap();
+        assert!(json.contains(r#""pattern":"0 . ~ -1 -2""#));
+        let back: Track = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.pattern, track.pattern);
+
+        // typed patterns still rest on -1
+        assert_eq!(["-1", ".", "~", "-2", "3"].map(parse_step), [Some(REST), Some(REST), Some(TIE), Some(-2), Some(3)]);
+
+        let old: Track = serde_json::from_str(r#"{"name":"t","pattern":[0,-1,-2,3,-5],"octave":3,"transpose":0,"waveform":"Saw","voice_spread":7}"#).unwrap();
+        assert_eq!(old.pattern, vec![0, REST, TIE, 3, REST]);
+    }
+
+    #[test]
+    fn inverted_copy_cancels_the_original() {
+        let mut seq = sine_seq(vec![0, 2, 4]);
+        let mut inverted = seq.tracks[0].clone();
+        inverted.name = "inv".to_string();
+        inverted.phase_invert = true;
+        // the original on its own, to show there's something to cancel
+        assert!(rms_over(&mut seq.clone(), 6) > 0.05);
+        seq.add_track(inverted);
+        seq.rewind();
+        assert!(rms_over(&mut seq, 6) < 1e-6);
+    }
+
+    #[test]
+    fn fm_adds_bessel_sidebands() {
+        // one second of a held sine voice, so every whole Hz is an exact bin
+        let render = |ratio: f32, index: f32| {
+            let mut v = Voice::new();
+            v.waveform = Waveform::Sine;
+            v.set_adsr(0.0, 0.0, 1.0, 0.1);
+            v.set_fm(ratio, index);
+            v.set_frequency(1000.0);
+            v.reset_env();
+            for _ in 0..100 { v.process(SR); }
+            (0..SR as usize).map(|_| v.process(SR) / v.amp).collect::<Vec<f32>>()
+        };
+        // amplitude of the `hz` component
+        let level = |samples: &[f32], hz: f32| {
+            let (re, im) = samples.iter().enumerate().fold((0.0f32, 0.0f32), |(re, im), (n, &x)| {
+                let w = 2.0 * PI * hz * n as f32 / SR;
+                (re + x * w.cos(), im - x * w.sin())
+            });
+            2.0 * re.hypot(im) / samples.len() as f32
+        };
+
+        // 1 kHz carrier, 300 Hz modulator, index 1: J0(1) = 0.765 on the
+        // carrier, J1(1) = 0.440 and J2(1) = 0.115 on either side
+        let fm = render(0.3, 1.0);
+        assert!(approx(level(&fm, 1000.0), 0.765, 0.01));
+        for hz in [700.0, 1300.0] { assert!(approx(level(&fm, hz), 0.440, 0.01), "{hz} Hz"); }
+        for hz in [400.0, 1600.0] { assert!(approx(level(&fm, hz), 0.115, 0.01), "{hz} Hz"); }
+
+        // index 0 is the plain carrier, sample for sample
+        let plain = render(1.0, 0.0);
+        assert_eq!(render(0.3, 0.0), plain);
+        assert!(approx(level(&plain, 1000.0), 1.0, 0.01));
+        assert!(level(&plain, 1300.0) < 0.01);
+    }
+
+    #[test]
+    fn progressions_hold_a_triad_per_bar() {
+        let (pattern, chords) = progression_pattern(&["I", "vi"], 4).unwrap();
+        assert_eq!(pattern, vec![0, TIE, TIE, TIE, 5, TIE, TIE, TIE]);
+        assert_eq!(chords[0], vec![0, 2, 4]);
+        assert_eq!(chords[4], vec![5, 7, 9]);
+        assert!(chords.iter().enumerate().all(|(i, c)| i % 4 == 0 || c.is_empty()));
+        assert!(progression_pattern(&["I", "IX"], 4).is_none());
+
+        // all three notes sound together
+        let mut seq = seq_with(pattern);
+        seq.tracks[0].chords = chords;
+        seq.rewind();
+        run_steps(&mut seq, 1);
+        let held = seq.voices[0].iter().filter(|v| v.is_held()).count();
+        assert_eq!(held, seq.tracks[0].voice_count());
+        assert_eq!(seq.used_notes().len(), 6);
+    }
+
+    #[test]
+    fn swapped_out_tracks_come_back_to_the_caller() {
+        let names = |tracks: &[Track]| tracks.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+        let mut seq = Sequencer::new(SR);
+        let first = names(&seq.tracks);
+        seq.reserve_tracks(4);
+        let lists = seq.tracks.as_ptr();
+
+        let mut parts = Vec::with_capacity(3);
+        parts.extend(["b", "c"].map(|n| TrackParts::new(Track::new(n), SR)));
+        let room = parts.as_ptr();
+        seq.swap_tracks(&mut parts);
+        assert_eq!(names(&seq.tracks), vec!["b", "c"]);
+        assert_eq!(parts.iter().map(|p| p.track.name.clone()).collect::<Vec<_>>(), first);
+        // neither side reallocated
+        assert_eq!((seq.tracks.as_ptr(), parts.as_ptr()), (lists, room));
+
+        let mut wider = Track::new("b");
+        wider.gain = 0.5;
+        wider.chords = vec![vec![0, 2, 4, 6, 8]];
+        let old = seq.upsert_parts(TrackParts::new(wider, SR)).expect("b exists");
+        assert_eq!((old.track.name.as_str(), old.track.gain), ("b", 1.0));
+        assert_eq!((seq.tracks[0].gain, seq.voices[0].len()), (0.5, 5));
+        assert!(seq.upsert_parts(TrackParts::new(Track::new("d"), SR)).is_none());
+
+        assert_eq!(seq.remove_track(1).track.name, "c");
+        assert_eq!(names(&seq.tracks), vec!["b", "d"]);
+        assert_eq!(seq.steps.len(), 2);
+    }
+
+    #[test]
+    fn limiter_keeps_the_master_under_full_scale() {
+        let bus = MasterBus { gain: db_to_gain(12.0) };
+        let mut last = 0.0;
+        for i in 0..=1000 {
+            let x = i as f32 / 100.0;
+            let y = bus.process(x);
+            assert!(y <= 1.0, "{x} -> {y}");
+            assert!(y >= last, "never folds back");
+            assert_eq!(bus.process(-x), -y);
+            last = y;
+        }
+        // below the knee the gain is all that happens
+        let unity = MasterBus::default();
+        assert_eq!(unity.process(0.5), 0.5);
+
+        // sixteen loud saws stacked on one note
+        let mut seq = seq_with(vec![0]);
+        for n in 0..16 {
+            let mut t = seq.tracks[0].clone();
+            t.name = format!("t{n}");
+            t.gain = 4.0;
+            seq.add_track(t);
+        }
+        seq.rewind();
+        let loudest = (0..seq.samples_per_step * 2)
+            .map(|_| { let (l, r) = seq.process(); l.abs().max(r.abs()) })
+            .fold(0.0, f32::max);
+        assert!(loudest > LIMITER_KNEE && loudest <= 1.0, "{loudest}");
+    }
+
+    #[test]
+    fn delay_echoes_land_at_the_set_time() {
+        let mut delay = Delay::new(SR);
+        delay.set(0.25, 0.5, 0.8);
+        let lag = (0.25 * SR) as usize;
+        // an impulse, then silence
+        let out: Vec<f32> = (0..3 * lag + 10).map(|n| delay.process(if n == 0 { 1.0 } else { 0.0 })).collect();
+        let hits: Vec<(usize, f32)> = out.iter().copied().enumerate().filter(|&(_, x)| x != 0.0).collect();
+        // dry, then each repeat at mix, fed back at half the level
+        assert_eq!(hits, vec![(0, 1.0), (lag, 0.8), (2 * lag, 0.4), (3 * lag, 0.2)]);
+    }
+
+    #[test]
+    fn bitcrush_at_16_bits_full_rate_is_transparent() {
+        let sine: Vec<f32> = (0..1000).map(|n| 0.9 * (2.0 * PI * 440.0 * n as f32 / SR).sin()).collect();
+        let mut crush = BitCrush::default();
+        crush.set(16, 1);
+        // within half a 16-bit step of the input, sample for sample
+        for &x in &sine {
+            assert!(approx(crush.process(x), x, 1.0 / 65535.0), "{x}");
+        }
+        assert!(!Track::new("t").is_crushed(), "and the mix skips it entirely");
+
+        // coarser settings do change the signal
+        crush.set(3, 4);
+        let out: Vec<f32> = sine.iter().map(|&x| crush.process(x)).collect();
+        assert!(out.chunks(4).all(|c| c.iter().all(|&y| y == c[0])), "held for 4 samples");
+        let mut levels: Vec<i32> = out.iter().map(|y| (y * 1000.0).round() as i32).collect();
+        levels.sort();
+        levels.dedup();
+        assert!(levels.len() <= 8);
+    }
+
+    #[test]
+    fn random_tracks_replay_from_the_sequencer_seed() {
+        let pattern = |seed: u64| {
+            let mut seq = Sequencer::new(SR);
+            seq.reseed(seed);
+            let first = random_track_with_len("r", 7, 16, &mut seq.rng);
+            let second = random_track("r", 7, &mut seq.rng);
+            (first.pattern, first.waveform, second.pattern)
+        };
+        let (first, _, second) = pattern(42);
+        assert_eq!(pattern(42), pattern(42));
+        assert_ne!(pattern(42), pattern(43));
+        assert_eq!((first.len(), second.len()), (16, RANDOM_STEPS));
+        assert_eq!(first[0], 0, "starts on the root");
+        assert!(first.iter().all(|&n| n == REST || n == TIE || (0..7).contains(&n)));
+    }
+
+    #[test]
+    fn reverb_tail_stays_finite_and_dies_away() {
+        // a second of full-scale noise, then three of silence
+        let render = |room: f32| {
+            let mut reverb = Reverb::new(SR, 0);
+            reverb.set(room, 0.5, 1.0);
+            let mut rng = Rng::new(1);
+            (0..4 * SR as usize)
+                .map(|n| reverb.process(if n < SR as usize { rng.bipolar() } else { 0.0 }))
+                .collect::<Vec<f32>>()
+        };
+        let rms = |x: &[f32], from: f32, to: f32| {
+            let part = &x[(from * SR) as usize..(to * SR) as usize];
+            (part.iter().map(|v| v * v).sum::<f32>() / part.len() as f32).sqrt()
+        };
+        for room in [0.0, 0.5, 1.0] {
+            let out = render(room);
+            assert!(out.iter().all(|x| x.is_finite() && x.abs() < 4.0), "room {room}");
+            // there is a tail, and it keeps falling
+            let (early, mid, late) = (rms(&out, 1.0, 1.2), rms(&out, 2.0, 2.2), rms(&out, 3.8, 4.0));
+            assert!(early > 1e-3 && mid < early && late < mid, "room {room}: {early} {mid} {late}");
+        }
+        let medium = render(0.5);
+        assert!(rms(&medium, 3.8, 4.0) < rms(&medium, 1.0, 1.2) * 1e-3, "a medium room is silent in 3 s");
+    }
+
+    #[test]
+    fn width_spreads_voices_without_detune() {
+        let side_and_mid = |width: f32| {
+            let mut seq = sine_seq(vec![0]);
+            seq.tracks[0].detune_cents = 0.0;
+            seq.tracks[0].unison_width = width;
+            let (mut side, mut mid) = (0.0, 0.0);
+            for _ in 0..seq.samples_per_step {
+                let (l, r) = seq.process();
+                side += (l - r).powi(2);
+                mid += (l + r).powi(2);
+            }
+            (side, mid)
+        };
+        let (side, mid) = side_and_mid(0.0);
+        assert!(side < 1e-9 && mid > 0.0, "width 0 is mono");
+        let (side, mid) = side_and_mid(1.0);
+        assert!(side > mid * 0.01, "the stacked voices pan apart: side {side}, mid {mid}");
+    }
+
+    #[test]
+    fn combining_kick_and_snare_merges_their_hits() {
+        let kick = vec![0, REST, REST, REST, 0, REST, REST, REST];
+        let snare = vec![REST, REST, 5, REST, REST, REST, 5, REST];
+        assert_eq!(combine_patterns(&kick, &snare), vec![0, REST, 5, REST, 0, REST, 5, REST]);
+
+        // a three-step figure against four steps runs for twelve
+        let three = vec![0, REST, REST];
+        let four = vec![REST, 3, REST, 3];
+        let merged = combine_patterns(&three, &four);
+        assert_eq!(merged.len(), 12);
+        assert_eq!(merged, vec![0, 3, REST, 0, REST, 3, 0, 3, REST, 0, REST, 3]);
+
+        // where both hit, the first pattern wins
+        assert_eq!(combine_patterns(&[1, 2, REST], &[7, REST, 7]), vec![1, 2, 7]);
+        assert_eq!(combine_patterns(&[7, REST, 7], &[1, 2, REST]), vec![7, 2, 7]);
+        assert_eq!(combine_patterns(&[], &snare), snare);
+    }
+
+    #[test]
+    fn seven_eight_bars_end_on_the_fourteenth_step() {
+        let mut seq = seq_with(vec![0]);
+        assert_eq!(seq.steps_per_bar(), 16, "4/4 by default");
+        assert!(seq.set_time_signature(7, 8));
+        assert_eq!(seq.steps_per_sig_beat(), 2);
+        assert_eq!(seq.steps_per_bar(), 14);
+        seq.rewind();
+        run_steps(&mut seq, 13);
+        assert_eq!((seq.bar, seq.bar_step), (0, 13));
+        run_steps(&mut seq, 1);
+        assert!(seq.is_bar_start());
+        assert_eq!(seq.bar, 1);
+
+        // beats that don't split into whole steps are refused, not rounded
+        assert!(!seq.set_time_signature(4, 32));
+        assert!(!seq.set_time_signature(0, 4));
+        assert!(!seq.set_steps_per_beat(3), "3 steps per beat can't make an 8th");
+        assert_eq!((seq.time_sig_num, seq.time_sig_den, seq.steps_per_beat), (7, 8, 4));
+        assert!(seq.set_steps_per_beat(8));
+        assert!(seq.set_time_signature(4, 32));
+
+        // and it survives a save and load
+        assert!(seq.set_time_signature(7, 8));
+        let loaded = Sequencer::from_project(serde_json::from_str(&serde_json::to_string(&seq.to_project()).unwrap()).unwrap(), SR);
+        assert_eq!((loaded.time_sig_num, loaded.time_sig_den, loaded.steps_per_bar()), (7, 8, 28));
+        let old: ProjectData = serde_json::from_str(r#"{"tracks":[],"scale":[0,2,3],"bpm":120.0}"#).unwrap();
+        assert_eq!((old.time_sig_num, old.time_sig_den), (4, 4));
+    }
+
+    #[test]
+    fn panic_silences_every_held_voice() {
+        let mut seq = sine_seq(vec![0]);
+        for name in ["pad", "lead"] {
+            let mut track = seq.tracks[0].clone();
+            track.name = name.to_string();
+            track.pattern = vec![2];
+            track.release = 2.0;
+            seq.add_track(track);
+        }
+        seq.rewind();
+        for _ in 0..seq.samples_per_step / 2 { seq.process(); }
+        assert_eq!(seq.voices.len(), 3);
+        assert!(seq.voices.iter().all(|pool| pool.iter().any(Voice::is_held)), "every track is holding a note");
+
+        seq.panic();
+        assert!(seq.voices.iter().flatten().all(|v| !v.is_held()), "nothing is held after a panic");
+        // well inside the step, so nothing retriggers
+        for _ in 0..(2.0 * PANIC_FADE * SR) as usize { seq.process(); }
+        assert!(seq.voices.iter().flatten().all(|v| v.is_silent() || v.is_finished()));
+        let (l, r) = seq.process();
+        assert!(l.abs() < 1e-6 && r.abs() < 1e-6, "{l} {r}");
+        assert_eq!(seq.tracks.len(), 3, "tracks are left alone");
+    }
+
+    #[test]
+    fn bass_follows_chord_roots_two_octaves_down() {
+        let scale = minor_scale("g");
+        let mut chords = Track::new("keys");
+        chords.octave = 5;
+        chords.transpose = 2;
+        chords.pattern = vec![0, REST, 3, TIE, 4];
+        chords.chords = vec![vec![0, 2, 4], vec![], vec![3, 5, 7], vec![], vec![4, 6, 8]];
+
+        let bass = bass_from_track(&chords, "bass");
+        assert_eq!(bass.name, "bass");
+        assert_eq!(bass.pattern, chords.pattern, "same rhythm, rests and ties carried through");
+        assert!(bass.chords.is_empty(), "roots only");
+        assert_eq!(bass.octave, 3);
+        for (step, chord) in chords.chords.iter().enumerate().filter(|(_, c)| !c.is_empty()) {
+            let root = chords.stack_notes(chord, &scale, 1)[0];
+            let low = bass.stack_notes(&[bass.pattern[step]], &scale, 1)[0];
+            assert_eq!(low, root - 24, "step {step}");
+        }
+        // never drops below octave 2, but always goes at least one down
+        chords.octave = 3;
+        assert_eq!(bass_from_track(&chords, "b").octave, 2);
+    }
+
+    #[test]
+    fn stutter_repeats_its_slice_then_lets_the_input_through() {
+        let mut st = Stutter::new(SR);
+        for n in 0..100 { assert_eq!(st.process(n as f32), n as f32); }
+        // the last four samples, looped for ten
+        st.trigger(4, 10);
+        let out: Vec<f32> = (100..115).map(|n| st.process(n as f32)).collect();
+        assert_eq!(out, [96.0, 97.0, 98.0, 99.0, 96.0, 97.0, 98.0, 99.0, 96.0, 97.0, 110.0, 111.0, 112.0, 113.0, 114.0]);
+        assert!(!st.is_active());
+
+        // in the sequencer it lasts one bar
+        let mut seq = seq_with(vec![0, 2]);
+        run_steps(&mut seq, 2);
+        seq.stutter(0, 1);
+        let bar = seq.steps_per_bar();
+        run_steps(&mut seq, bar - 1);
+        assert!(seq.stutters[0].is_active());
+        run_steps(&mut seq, 1);
+        assert!(!seq.stutters[0].is_active());
+    }
+
+    #[test]
+    fn g_minor_reads_back_as_note_names() {
+        let names: Vec<String> = minor_scale("g").iter().map(|&n| semitone_to_note(n)).collect();
+        assert_eq!(names, ["G", "A", "A#", "C", "D", "D#", "F"]);
+        // any octave, either side of zero
+        assert_eq!(semitone_to_note(-5), "G");
+        assert_eq!(semitone_to_note(19), "G");
+        assert_eq!(semitone_to_note(0), "C");
+    }
+
+    #[test]
+    fn swing_range_delays_only_its_offbeats() {
+        let mut seq = seq_with(vec![0; 8]);
+        seq.tracks[0].swing_range(2, 5, 0.5);
+        let sps = seq.samples_per_step;
+        let delays: Vec<usize> = (0..8).map(|i| seq.tracks[0].step_delay(i, sps)).collect();
+        assert_eq!(delays, [0, 0, 0, sps / 2, 0, sps / 2, 0, 0]);
+
+        // in playback: samples into each step before its note restarts the envelope
+        seq.rewind();
+        let mut fired = Vec::new();
+        let mut last_env = f32::MAX;
+        while fired.len() < 8 {
+            seq.process();
+            let env = seq.voices[0][0].env_phase;
+            if env < last_env { fired.push(seq.sample_counter); }
+            last_env = env;
+        }
+        for (step, &at) in fired.iter().enumerate() {
+            let late = at > sps / 4;
+            assert_eq!(late, step == 3 || step == 5, "step {step} fired {at} samples in");
+        }
+    }
+
+    #[test]
+    fn auto_accent_lifts_the_downbeats() {
+        let peaks = |accent: f32| {
+            let mut seq = sine_seq(vec![0; 8]);
+            seq.tracks[0].gain = 0.1;
+            seq.auto_accent = accent;
+            seq.rewind();
+            step_peaks(&mut seq, 8)
+        };
+        let flat = peaks(0.0);
+        assert!(approx(flat[4], flat[5], 0.02 * flat[5]), "neutral at 0: {flat:?}");
+
+        let accented = peaks(0.5);
+        for (step, p) in accented.iter().enumerate() {
+            // a beat is four steps
+            let want = if step % 4 == 0 { flat[step] * 1.5 } else { flat[step] };
+            assert!(approx(*p, want, 0.05 * want), "step {step}: {accented:?}");
+        }
+    }
+
+    #[test]
+    fn new_root_moves_the_scale_and_keeps_the_mode() {
+        let mut seq = seq_with(vec![0]);
+        seq.set_root("D");
+        assert_eq!(seq.scale, vec![2, 4, 5, 7, 9, 10, 12], "D natural minor");
+        assert_eq!(seq.scale_name, Some(("minor".to_string(), "d".to_string())));
+
+        seq.set_scale("dorian", "c");
+        seq.set_root("e");
+        assert_eq!(seq.scale, scale_from_name("dorian", "e").unwrap());
+        assert_eq!(seq.scale, vec![4, 6, 7, 9, 11, 13, 14]);
+        assert_eq!(seq.scale_name, Some(("dorian".to_string(), "e".to_string())));
+
+        // and the new key is auditioned, root to octave
+        assert_eq!(seq.audition.notes.len(), 8);
+        assert!(approx(seq.audition.notes[0], midi_to_freq(64), 0.01));
+        assert!(approx(seq.audition.notes[7], midi_to_freq(76), 0.01));
+    }
+
+    #[test]
+    fn scheduled_changes_land_on_their_beat_not_before() {
+        let mut seq = seq_with(vec![0]);
+        let start = seq.bpm;
+        // bar 3 beat 2 is step 36 in 4/4; queued out of order
+        seq.schedule_change(3, 2, ParamChange::Bpm(140.0));
+        seq.schedule_change(2, 1, ParamChange::Drift(5.0));
+        assert_eq!(seq.schedule.iter().map(|c| (c.bar, c.beat)).collect::<Vec<_>>(), [(2, 1), (3, 2)]);
+
+        run_steps(&mut seq, 15);
+        assert_eq!(seq.analog_drift, 0.0, "last step of bar 1");
+        run_steps(&mut seq, 1);
+        assert_eq!(seq.analog_drift, 5.0, "on the downbeat of bar 2");
+
+        run_steps(&mut seq, 19);
+        assert_eq!((seq.bar, seq.bar_step), (2, 3));
+        assert_eq!(seq.bpm, start, "one step early");
+        run_steps(&mut seq, 1);
+        assert_eq!(seq.bpm, 140.0);
+        assert!(seq.schedule.is_empty());
+    }
+
+    #[test]
+    fn velocity_shapes_the_envelope_only_when_asked() {
+        // (envelope peak, samples to reach it, loudest output)
+        let hit = |velocity: f32, vel_to_env: f32| {
+            let mut v = Voice::new();
+            v.set_adsr(0.01, 0.1, 0.5, 0.1);
+            v.set_frequency(220.0);
+            v.set_waveform(Waveform::Square);
+            v.set_velocity(velocity, vel_to_env);
+            v.reset_env();
+            let (mut peak, mut at, mut loudest) = (0.0f32, 0, 0.0f32);
+            for n in 0..(0.05 * SR) as usize {
+                loudest = loudest.max(v.process(SR).abs());
+                if v.env_level > peak { (peak, at) = (v.env_level, n); }
+            }
+            (peak, at, loudest)
+        };
+        let (hard, soft) = (hit(1.0, 1.0), hit(0.5, 1.0));
+        assert!(hard.0 > soft.0 * 1.5, "harder peaks higher: {hard:?} {soft:?}");
+        assert!(hard.1 * 3 / 2 < soft.1, "and attacks faster: {hard:?} {soft:?}");
+        assert!(hard.2 > soft.2);
+
+        // at 0 the envelope is the same and only the level follows velocity
+        let (hard, soft) = (hit(1.0, 0.0), hit(0.5, 0.0));
+        assert_eq!((hard.0, hard.1), (soft.0, soft.1));
+        assert!(approx(soft.2, hard.2 * 0.5, 1e-3), "{hard:?} {soft:?}");
+    }
+
+    #[test]
+    fn velocity_curves_shape_each_bar() {
+        let peaks = |curve: VelocityCurve| {
+            let mut seq = sine_seq(vec![0; 16]);
+            seq.tracks[0].gain = 0.1;
+            seq.tracks[0].velocity_curve = curve;
+            seq.rewind();
+            step_peaks(&mut seq, 16)
+        };
+        let flat = peaks(VelocityCurve::Flat);
+        for curve in [VelocityCurve::Ramp, VelocityCurve::Fall, VelocityCurve::Sine] {
+            let shaped = peaks(curve);
+            for step in 0..16 {
+                let want = curve.gain(step as f32 / 16.0);
+                let got = shaped[step] / flat[step];
+                assert!(approx(got, want, 0.02), "{curve:?} step {step}: {got} vs {want}");
+            }
+        }
+        // the named shapes: ramp climbs, fall drops, sine swells mid-bar
+        assert_eq!(VelocityCurve::Ramp.gain(0.0), 0.5);
+        assert_eq!(VelocityCurve::Fall.gain(0.0), 1.0);
+        assert!(VelocityCurve::Sine.gain(0.25) > VelocityCurve::Sine.gain(0.75));
+        assert_eq!(VelocityCurve::from_name("RAMP"), Some(VelocityCurve::Ramp));
+        assert_eq!(VelocityCurve::from_name("wobble"), None);
+    }
+
+    #[test]
+    fn every_mode_has_its_own_intervals() {
+        let modes: [(&str, [i32; 7]); 7] = [
+            ("major", [0, 2, 4, 5, 7, 9, 11]),
+            ("minor", [0, 2, 3, 5, 7, 8, 10]),
+            ("dorian", [0, 2, 3, 5, 7, 9, 10]),
+            ("phrygian", [0, 1, 3, 5, 7, 8, 10]),
+            ("lydian", [0, 2, 4, 6, 7, 9, 11]),
+            ("mixolydian", [0, 2, 4, 5, 7, 9, 10]),
+            ("locrian", [0, 1, 3, 5, 6, 8, 10]),
+        ];
+        for (mode, steps) in modes {
+            assert_eq!(scale_from_name(mode, "c"), Some(steps.to_vec()), "{mode}");
+            let on_d: Vec<i32> = steps.iter().map(|n| n + 2).collect();
+            assert_eq!(scale_from_name(mode, "d"), Some(on_d), "{mode} on D");
+        }
+        assert_eq!(scale_from_name("blues", "c"), None);
+
+        // the sequencer falls back to minor on a name it doesn't know
+        let mut seq = seq_with(vec![0]);
+        seq.set_scale("blues", "c");
+        assert_eq!(seq.scale, minor_scale("c"));
+        assert_eq!(seq.scale_name, Some(("minor".to_string(), "c".to_string())));
+        // and a mode survives a save and load
+        seq.set_scale("phrygian", "e");
+        let loaded = Sequencer::from_project(seq.to_project(), SR);
+        assert_eq!(loaded.scale, scale_from_name("phrygian", "e").unwrap());
+    }
+}
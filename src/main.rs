@@ -1,351 +1,1108 @@
 use std::io::{self, Write};
-use std::time::Duration;
-use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+use std::f32::consts::FRAC_1_SQRT_2;
 use std::fs;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use dialoguer::{Select, Input, Confirm, theme::ColorfulTheme};
-use serde::{Deserialize, Serialize};
+use dialoguer::console::{Key, Term};
+use vibez::*;
 
 //
 // =========================
-//   V O I C E
+//   A U D I O
 // =========================
 //
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub enum Waveform { Sine, Saw, Square, Triangle }
-
-#[derive(Clone, Debug)]
-pub struct Voice {
-    phase: f32,
-    frequency: f32,
-    waveform: Waveform,
-    amp: f32,
-    // simple ADSR
-    attack: f32,
-    decay: f32,
-    sustain: f32,
-    release: f32,
-    env_phase: f32,
-}
-
-impl Voice {
-    pub fn new() -> Self {
-        Self {
-            phase: 0.0,
-            frequency: 440.0,
-            waveform: Waveform::Saw,
-            amp: 0.15,
-            attack: 0.01,
-            decay: 0.1,
-            sustain: 0.3,
-            release: 0.1,
-            env_phase: 0.0,
-        }
-    }
-
-    pub fn set_frequency(&mut self, freq: f32) { self.frequency = freq; }
-
-    pub fn process(&mut self, sample_rate: f32) -> f32 {
-        let sample = match self.waveform {
-            Waveform::Saw => 2.0 * (self.phase - 0.5),
-            Waveform::Sine => (2.0 * PI * self.phase).sin(),
-            Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
-            Waveform::Triangle => 1.0 - (4.0 * (self.phase - 0.25)).abs(),
-        };
+/// An edit for the audio thread, applied between buffers. Tracks arrive
+/// fully built, so applying one never allocates their buffers.
+pub enum AudioCommand {
+    AddTrack(TrackParts),
+    UpsertTrack(TrackParts),
+    DeleteTrack(usize),
+    /// A whole new track list (undo/redo, clear), with room for the old one
+    SwapTracks(Vec<TrackParts>),
+    /// A loaded project, its tracks built and its scale resolved
+    LoadProject(Box<ProjectData>, Vec<TrackParts>),
+    SetBpm(f32),
+    /// The whole section list after a save
+    SetSections(Vec<Section>),
+    SetArrangement(Arrangement),
+    /// A new scale with its (mode, root) name
+    SetScale(Vec<i32>, Option<(String, String)>),
+    /// Notes for the audition voice, e.g. a run through a new key
+    Audition(Vec<f32>),
+    /// Anything else, replayed on the audio thread's sequencer. It must not
+    /// allocate; the closure itself comes back with the garbage.
+    Edit(Box<dyn FnMut(&mut Sequencer) + Send>),
+}
 
-        self.phase += self.frequency / sample_rate;
-        if self.phase >= 1.0 { self.phase -= 1.0; }
+/// Whatever an edit swapped out on the audio thread, sent back so its
+/// memory is freed on the UI thread rather than in the callback.
+// only ever dropped, and boxing the big variant would mean allocating on
+// the audio thread
+#[allow(dead_code, clippy::large_enum_variant)]
+enum Garbage {
+    Track(TrackParts),
+    Tracks(Vec<TrackParts>),
+    Project(Box<ProjectData>, Vec<TrackParts>),
+    Sections(Vec<Section>),
+    Arrangement(Arrangement),
+    Scale(Vec<i32>, Option<(String, String)>),
+    Notes(Vec<f32>),
+    Edit(Box<dyn FnMut(&mut Sequencer) + Send>),
+}
 
-        // simple envelope
-        let env = if self.env_phase < self.attack {
-            self.env_phase / self.attack
-        } else if self.env_phase < self.attack + self.decay {
-            1.0 - ((self.env_phase - self.attack)/self.decay)*(1.0 - self.sustain)
-        } else {
-            self.sustain
+// tracks the audio thread can add before its per-track lists reallocate
+const TRACK_HEADROOM: usize = 64;
+
+impl AudioCommand {
+    fn apply(self, seq: &mut Sequencer, trash: &mpsc::Sender<Garbage>) {
+        let garbage = match self {
+            AudioCommand::AddTrack(parts) => { seq.add_parts(parts); None }
+            AudioCommand::UpsertTrack(parts) => seq.upsert_parts(parts).map(Garbage::Track),
+            AudioCommand::DeleteTrack(idx) => (idx < seq.tracks.len()).then(|| Garbage::Track(seq.remove_track(idx))),
+            AudioCommand::SwapTracks(mut parts) => {
+                seq.swap_tracks(&mut parts);
+                Some(Garbage::Tracks(parts))
+            }
+            AudioCommand::LoadProject(mut project, mut parts) => {
+                seq.swap_project(&mut project, &mut parts);
+                Some(Garbage::Project(project, parts))
+            }
+            AudioCommand::SetBpm(bpm) => {
+                seq.ramp = None;
+                seq.set_bpm(bpm);
+                None
+            }
+            AudioCommand::SetSections(mut sections) => {
+                std::mem::swap(&mut seq.sections, &mut sections);
+                Some(Garbage::Sections(sections))
+            }
+            AudioCommand::SetArrangement(mut arrangement) => {
+                seq.swap_arrangement(&mut arrangement);
+                Some(Garbage::Arrangement(arrangement))
+            }
+            AudioCommand::SetScale(mut scale, mut name) => {
+                seq.swap_scale(&mut scale, &mut name);
+                Some(Garbage::Scale(scale, name))
+            }
+            AudioCommand::Audition(notes) => {
+                let note_len = seq.samples_per_step;
+                Some(Garbage::Notes(seq.audition.play(notes, note_len)))
+            }
+            AudioCommand::Edit(mut f) => {
+                f(seq);
+                Some(Garbage::Edit(f))
+            }
         };
+        // with the UI gone there's no one to hand it to; drop it here
+        if let Some(garbage) = garbage {
+            let _ = trash.send(garbage);
+        }
+    }
+}
 
-        self.env_phase += 1.0 / sample_rate;
+/// UI side of the sequencer. Reads come from a local copy; every edit is
+/// applied to that copy and sent to the audio thread, so the two never
+/// share a lock. Only the meters, stats and garbage flow back.
+pub struct SeqHandle {
+    model: RefCell<Sequencer>,
+    tx: mpsc::Sender<AudioCommand>,
+    trash: mpsc::Receiver<Garbage>,
+    levels: Arc<Mutex<Vec<Meter>>>,
+    stats: Arc<Stats>,
+}
 
-        sample * self.amp * env
-    }
+/// Audio-thread side: sole owner of the sequencer that actually plays.
+pub struct AudioEngine {
+    seq: Sequencer,
+    rx: mpsc::Receiver<AudioCommand>,
+    trash: mpsc::Sender<Garbage>,
+    levels: Arc<Mutex<Vec<Meter>>>,
+    stats: Arc<Stats>,
+    // when the previous buffer was requested and how long it lasts
+    last_callback: Option<(cpal::StreamInstant, f32)>,
+}
 
-    pub fn reset_env(&mut self) { self.env_phase = 0.0; }
+/// Load figures from the audio thread, kept in atomics so publishing them
+/// never blocks the callback.
+#[derive(Debug, Default)]
+pub struct Stats {
+    active_voices: AtomicUsize,
+    // f32 bits of the loudest sample in the last buffer
+    peak: AtomicU32,
+    underruns: AtomicU64,
 }
 
-//
-// =========================
-//   S C A L E + UTILS
-// =========================
-//
+// a callback this much later than the previous buffer could cover counts
+// as an underrun (the margin absorbs scheduling jitter)
+const LATE_CALLBACK: f32 = 1.5;
+
+impl Stats {
+    pub fn active_voices(&self) -> usize { self.active_voices.load(Ordering::Relaxed) }
 
-fn midi_to_freq(n: i32) -> f32 { 440.0 * 2f32.powf((n as f32 - 69.0)/12.0) }
+    pub fn peak(&self) -> f32 { f32::from_bits(self.peak.load(Ordering::Relaxed)) }
 
-fn minor_scale(root: &str) -> Vec<i32> {
-    let r = note_to_semitone(root);
-    vec![0,2,3,5,7,8,10].iter().map(|x| x+r).collect()
+    pub fn underruns(&self) -> u64 { self.underruns.load(Ordering::Relaxed) }
 }
 
-fn note_to_semitone(name: &str) -> i32 {
-    match name.to_lowercase().as_str() {
-        "c"=>0,"c#"|"db"=>1,"d"=>2,"d#"|"eb"=>3,"e"=>4,"f"=>5,"f#"|"gb"=>6,
-        "g"=>7,"g#"|"ab"=>8,"a"=>9,"a#"|"bb"=>10,"b"=>11,_=>0
+impl SeqHandle {
+    pub fn new(seq: Sequencer) -> (Self, AudioEngine) {
+        let (tx, rx) = mpsc::channel();
+        let (trash_tx, trash) = mpsc::channel();
+        let levels = Arc::new(Mutex::new(seq.meters.clone()));
+        let stats = Arc::new(Stats::default());
+        let mut engine_seq = seq.clone();
+        engine_seq.reserve_tracks(TRACK_HEADROOM);
+        let engine = AudioEngine {
+            seq: engine_seq,
+            rx,
+            trash: trash_tx,
+            levels: levels.clone(),
+            stats: stats.clone(),
+            last_callback: None,
+        };
+        (Self { model: RefCell::new(seq), tx, trash, levels, stats }, engine)
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&Sequencer) -> R) -> R {
+        f(&self.model.borrow())
+    }
+
+    /// Run `f` on the local copy (returning its result) and queue the same
+    /// edit for the audio thread. There `f` must neither allocate nor free,
+    /// and its result is dropped, so it should own no heap memory; edits
+    /// that replace owned data have their own commands.
+    pub fn edit<R>(&self, f: impl Fn(&mut Sequencer) -> R + Send + 'static) -> R {
+        let result = f(&mut self.model.borrow_mut());
+        self.send(AudioCommand::Edit(Box::new(move |s| { f(s); })));
+        result
+    }
+
+    /// A track with its voices and buffers, built here for the audio thread.
+    fn parts(&self, track: Track) -> TrackParts {
+        TrackParts::new(track, self.model.borrow().sample_rate)
+    }
+
+    /// Run `f` on the local copy only, for randomness the audio thread
+    /// doesn't need: `random` draws from this copy's generator, so a seed
+    /// replays the same tracks however the playing steps have rolled.
+    pub fn generate<R>(&self, f: impl FnOnce(&mut Sequencer) -> R) -> R {
+        f(&mut self.model.borrow_mut())
+    }
+
+    pub fn add_track(&self, track: Track) {
+        let parts = self.parts(track);
+        self.model.borrow_mut().add_parts(parts.clone());
+        self.send(AudioCommand::AddTrack(parts));
+    }
+
+    /// See `Sequencer::upsert_track`; true if an existing track was replaced.
+    pub fn upsert_track(&self, track: Track) -> bool {
+        let parts = self.parts(track);
+        let replaced = self.model.borrow_mut().upsert_parts(parts.clone()).is_some();
+        self.send(AudioCommand::UpsertTrack(parts));
+        replaced
+    }
+
+    pub fn remove_track(&self, idx: usize) {
+        self.model.borrow_mut().remove_track(idx);
+        self.send(AudioCommand::DeleteTrack(idx));
+    }
+
+    /// Swap in a whole track list (undo/redo).
+    pub fn restore_tracks(&self, tracks: Vec<Track>) {
+        // room for the old list to come back in without allocating
+        let mut parts = Vec::with_capacity(tracks.len() + self.read(|s| s.tracks.len()));
+        parts.extend(tracks.into_iter().map(|t| self.parts(t)));
+        self.model.borrow_mut().swap_tracks(&mut parts.clone());
+        self.send(AudioCommand::SwapTracks(parts));
+    }
+
+    pub fn clear_tracks(&self) {
+        self.restore_tracks(Vec::new());
+    }
+
+    /// See `Sequencer::replace_project`.
+    pub fn replace_project(&self, mut project: ProjectData) {
+        project.scale = project.resolved_scale();
+        let mut parts = Vec::with_capacity(project.tracks.len() + self.read(|s| s.tracks.len()));
+        parts.extend(project.tracks.drain(..).map(|t| self.parts(t)));
+        self.model.borrow_mut().swap_project(&mut project.clone(), &mut parts.clone());
+        self.send(AudioCommand::LoadProject(Box::new(project), parts));
+    }
+
+    /// Save the tracks as section `name`; the new list is built here.
+    pub fn save_section(&self, name: &str) {
+        let sections = self.read(|s| s.sections_with(name));
+        self.model.borrow_mut().sections.clone_from(&sections);
+        self.send(AudioCommand::SetSections(sections));
+    }
+
+    pub fn set_arrangement(&self, arrangement: Arrangement) {
+        self.model.borrow_mut().set_arrangement(arrangement.clone());
+        self.send(AudioCommand::SetArrangement(arrangement));
+    }
+
+    /// Change the scale with `f` (`set_scale`, `set_root`, ...) on the
+    /// local copy and send the result over whole. Returns the new scale.
+    pub fn change_scale(&self, f: impl FnOnce(&mut Sequencer)) -> Vec<i32> {
+        let mut model = self.model.borrow_mut();
+        f(&mut model);
+        let (scale, name) = (model.scale.clone(), model.scale_name.clone());
+        drop(model);
+        self.send(AudioCommand::SetScale(scale.clone(), name));
+        scale
+    }
+
+    /// Play the current scale once through the audition voice.
+    pub fn audition_scale(&self) {
+        let run = self.read(Sequencer::scale_run);
+        self.send(AudioCommand::Audition(run));
+    }
+
+    /// Set the tempo now, cancelling any ramp.
+    pub fn set_bpm(&self, bpm: f32) {
+        let mut model = self.model.borrow_mut();
+        model.ramp = None;
+        model.set_bpm(bpm);
+        drop(model);
+        self.send(AudioCommand::SetBpm(bpm));
+    }
+
+    /// Latest per-track levels from the audio thread.
+    pub fn meters(&self) -> Vec<Meter> {
+        self.levels.lock().map(|l| l.clone()).unwrap_or_default()
+    }
+
+    /// Live voice count, peak level and underruns from the audio thread.
+    pub fn stats(&self) -> &Stats { &self.stats }
+
+    fn send(&self, cmd: AudioCommand) {
+        // free whatever the audio thread has finished with
+        while self.trash.try_recv().is_ok() {}
+        // no audio thread (e.g. no device) just means nothing to keep in sync
+        let _ = self.tx.send(cmd);
     }
 }
 
-//
-// =========================
-//   T R A C K
-// =========================
-//
+impl AudioEngine {
+    /// Apply queued edits, render one buffer, publish the meters. Never
+    /// blocks: the levels are skipped for a buffer if the UI is reading them.
+    pub fn fill<T: Copy>(&mut self, data: &mut [T], channels: usize, convert: impl Fn(f32) -> T) {
+        while let Ok(cmd) = self.rx.try_recv() {
+            cmd.apply(&mut self.seq, &self.trash);
+        }
+        let mut peak = 0.0f32;
+        fill_frames(data, channels, || {
+            let (l, r) = self.seq.process();
+            peak = peak.max(l.abs()).max(r.abs());
+            (l, r)
+        }, convert);
+        if let Ok(mut levels) = self.levels.try_lock() {
+            levels.clone_from(&self.seq.meters);
+        }
+        self.stats.peak.store(peak.to_bits(), Ordering::Relaxed);
+        self.stats.active_voices.store(self.seq.active_voices(), Ordering::Relaxed);
+    }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Track {
-    pub name: String,
-    pub pattern: Vec<i32>,
-    pub octave: i32,
-    pub transpose: i32,
-    pub waveform: Waveform,
-    pub voice_spread: i32,
+    /// Count an underrun if this buffer was asked for after the previous
+    /// one would have run out.
+    fn check_timing(&mut self, now: cpal::StreamInstant, frames: usize) {
+        if let Some((prev, covered)) = self.last_callback
+            && now.duration_since(&prev).is_some_and(|gap| gap.as_secs_f32() > covered * LATE_CALLBACK)
+        {
+            self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_callback = Some((now, frames as f32 / self.seq.sample_rate));
+    }
 }
 
-impl Track {
-    pub fn new(name: &str) -> Self {
-        Self {
-            name: name.to_string(),
-            pattern: vec![0],
-            octave: 3,
-            transpose: 0,
-            waveform: Waveform::Saw,
-            voice_spread: 7,
+/// Write one stereo frame per interleaved frame, so the sequencer advances
+/// once per frame whatever the layout. Mono devices get a downmix.
+fn fill_frames<T: Copy>(
+    data: &mut [T],
+    channels: usize,
+    mut next: impl FnMut() -> (f32, f32),
+    convert: impl Fn(f32) -> T,
+) {
+    for frame in data.chunks_mut(channels.max(1)) {
+        let (l, r) = next();
+        if let [left, right, rest @ ..] = frame {
+            *left = convert(l);
+            *right = convert(r);
+            // centre/surround channels get the mid signal
+            rest.fill(convert(downmix(l, r)));
+        } else {
+            frame.fill(convert(downmix(l, r)));
         }
     }
 }
 
-//
-// =========================
-//   S E Q U E N C E R
-// =========================
-//
+/// Mono from stereo that keeps a centre-panned track at its original level.
+fn downmix(l: f32, r: f32) -> f32 { (l + r) * FRAC_1_SQRT_2 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ProjectData {
-    pub tracks: Vec<Track>,
-    pub scale: Vec<i32>,
-    pub bpm: f32,
+/// Most channels any output config of the device supports.
+fn max_output_channels(device: &cpal::Device) -> Option<u16> {
+    device.supported_output_configs().ok()?.map(|c| c.channels()).max()
 }
 
-#[derive(Clone, Debug)]
-pub struct Sequencer {
-    pub tracks: Vec<Track>,
-    pub scale: Vec<i32>,
-    pub voices: Vec<Vec<Voice>>,
+/// Preferred sample rate of the named output device (or the default one).
+fn device_sample_rate(device_name: Option<&str>) -> Option<f32> {
+    let host = cpal::default_host();
+    let device = match device_name {
+        Some(name) => host.output_devices().ok()?.find(|d| d.name().is_ok_and(|n| n == name)),
+        None => None,
+    }.or_else(|| host.default_output_device())?;
+    Some(device.default_output_config().ok()?.sample_rate().0 as f32)
+}
 
-    pub sample_rate: f32,
-    pub step: usize,
-    pub samples_per_step: usize,
-    pub sample_counter: usize,
+/// Names of every output device on the default host.
+fn list_output_devices() -> Vec<String> {
+    let host = cpal::default_host();
+    host.output_devices()
+        .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+        .unwrap_or_default()
 }
 
-impl Sequencer {
-    pub fn new(sample_rate: f32) -> Self {
-        Self {
-            tracks: vec![Track::new("Main")],
-            scale: minor_scale("g"),
-            voices: vec![vec![Voice::new(); 3]],
-            sample_rate,
-            step: 0,
-            samples_per_step: (sample_rate/4.0) as usize,
-            sample_counter: 0,
+/// Why audio output couldn't start.
+#[derive(Debug)]
+pub enum AudioError {
+    NoDevice,
+    Config(cpal::DefaultStreamConfigError),
+    UnsupportedFormat(cpal::SampleFormat),
+    BuildStream(cpal::BuildStreamError),
+    PlayStream(cpal::PlayStreamError),
+}
+
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AudioError::NoDevice => write!(f, "no output device available"),
+            AudioError::Config(e) => write!(f, "couldn't read the device config: {e}"),
+            AudioError::UnsupportedFormat(fmt) => write!(f, "unsupported sample format {fmt}"),
+            AudioError::BuildStream(e) => write!(f, "couldn't build the output stream: {e}"),
+            AudioError::PlayStream(e) => write!(f, "couldn't start the output stream: {e}"),
         }
     }
+}
 
-    pub fn from_project(project: ProjectData, sample_rate: f32) -> Self {
-        let num_tracks = project.tracks.len();
-        let mut voices = Vec::new();
-        for _ in 0..num_tracks {
-            voices.push(vec![Voice::new(); 3]);
+impl std::error::Error for AudioError {}
+
+/// Play until the process exits (`--play`).
+fn play_audio(engine: AudioEngine, channels: Option<u16>, device_name: Option<String>) -> Result<(), AudioError> {
+    let _stream = start_audio(engine, channels, device_name)?;
+    // Silently run - don't print to console
+    loop { std::thread::sleep(Duration::from_secs(1)); }
+}
+
+/// Start streaming on the named device, or the default one. If the named
+/// device is missing or won't open, say so and fall back to the default.
+/// The stream plays for as long as the returned handle is kept.
+fn start_audio(engine: AudioEngine, channels: Option<u16>, device_name: Option<String>) -> Result<cpal::Stream, AudioError> {
+    let host = cpal::default_host();
+    let chosen = device_name.as_deref().and_then(|name| {
+        let found = host.output_devices().ok()?.find(|d| d.name().is_ok_and(|n| n == name));
+        if found.is_none() {
+            println!("✗ Output device '{}' not found, using the default", name);
         }
-        
-        Self {
-            tracks: project.tracks,
-            scale: project.scale,
-            voices,
-            sample_rate,
-            step: 0,
-            samples_per_step: (sample_rate * 60.0 / project.bpm / 4.0) as usize,
-            sample_counter: 0,
+        found.map(|d| (name, d))
+    });
+    let opened = match chosen.map(|(name, d)| (name, build_stream(&d, channels))) {
+        Some((_, Ok(opened))) => opened,
+        other => {
+            if let Some((name, Err(e))) = other {
+                println!("✗ Couldn't open '{}' ({}), falling back to the default device", name, e);
+            }
+            let device = host.default_output_device().ok_or(AudioError::NoDevice)?;
+            build_stream(&device, channels)?
         }
+    };
+    let (stream, engine_tx, stream_rate) = opened;
+    let mut engine = engine;
+    if engine.seq.sample_rate != stream_rate {
+        println!("⚠ Sequencer runs at {} Hz but the device at {} Hz; retuning to match",
+            engine.seq.sample_rate, stream_rate);
+        engine.seq.set_sample_rate(stream_rate);
     }
+    // the callback picks the engine up on its next buffer
+    let _ = engine_tx.send(engine);
 
-    pub fn add_track(&mut self, track: Track) {
-        self.tracks.push(track);
-        self.voices.push(vec![Voice::new(); 3]);
-    }
+    stream.play().map_err(AudioError::PlayStream)?;
+    Ok(stream)
+}
 
-    pub fn process(&mut self) -> f32 {
-        self.sample_counter += 1;
-        if self.sample_counter >= self.samples_per_step {
-            self.sample_counter = 0;
-            self.step = (self.step + 1) % self.get_max_pattern_len();
-            self.trigger_step();
+/// Build (without starting) an output stream on `device`, returning it
+/// with its sample rate. The engine is sent through the returned channel
+/// once the stream exists, so a failed attempt doesn't swallow it.
+fn build_stream(
+    device: &cpal::Device,
+    channels: Option<u16>,
+) -> Result<(cpal::Stream, mpsc::Sender<AudioEngine>, f32), AudioError> {
+    let config = device.default_output_config().map_err(AudioError::Config)?;
+    let max = channels.and_then(|_| max_output_channels(device));
+    let cfg = stream_config(&config, channels, max)?;
+    let err_fn = |err| eprintln!("stream error: {err}");
+    let ch = cfg.channels as usize;
+
+    let (tx, rx) = mpsc::channel();
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => {
+            device.build_output_stream(&cfg, engine_callback(rx, ch, |x| x), err_fn, None)
         }
+        cpal::SampleFormat::I16 => {
+            device.build_output_stream(&cfg, engine_callback(rx, ch, |x| (x*i16::MAX as f32) as i16), err_fn, None)
+        }
+        cpal::SampleFormat::U16 => {
+            device.build_output_stream(&cfg, engine_callback(rx, ch, |x| {
+                let v = (x*0.5+0.5).clamp(0.0,1.0);
+                (v*u16::MAX as f32) as u16
+            }), err_fn, None)
+        }
+        other => unreachable!("stream_config rejects {other}"),
+    };
+    Ok((stream.map_err(AudioError::BuildStream)?, tx, cfg.sample_rate.0 as f32))
+}
 
-        // mix all tracks
-        let mut sum = 0.0;
-        let mut voice_count = 0;
-        for voices in &mut self.voices {
-            for v in voices {
-                sum += v.process(self.sample_rate);
-                voice_count += 1;
-            }
+/// The config to open a device with, given its default: a requested
+/// channel count is clamped to 1..=`max_channels` (the default's count if
+/// unknown), and sample formats the callback can't write are refused.
+fn stream_config(
+    default: &cpal::SupportedStreamConfig,
+    channels: Option<u16>,
+    max_channels: Option<u16>,
+) -> Result<cpal::StreamConfig, AudioError> {
+    let format = default.sample_format();
+    if !matches!(format, cpal::SampleFormat::F32 | cpal::SampleFormat::I16 | cpal::SampleFormat::U16) {
+        return Err(AudioError::UnsupportedFormat(format));
+    }
+    let mut cfg = default.config();
+    if let Some(n) = channels {
+        let max = max_channels.unwrap_or(cfg.channels);
+        cfg.channels = n.clamp(1, max.max(1));
+    }
+    Ok(cfg)
+}
+
+/// Stream callback that outputs silence until an engine arrives on `rx`,
+/// then renders through it.
+fn engine_callback<T: Copy + Send + 'static>(
+    rx: mpsc::Receiver<AudioEngine>,
+    channels: usize,
+    convert: fn(f32) -> T,
+) -> impl FnMut(&mut [T], &cpal::OutputCallbackInfo) + Send + 'static {
+    let mut engine: Option<AudioEngine> = None;
+    move |data, info| {
+        if engine.is_none() {
+            engine = rx.try_recv().ok();
         }
-        if voice_count > 0 {
-            sum / voice_count as f32
-        } else {
-            0.0
+        match &mut engine {
+            Some(engine) => {
+                engine.check_timing(info.timestamp().callback, data.len() / channels.max(1));
+                engine.fill(data, channels, convert);
+            }
+            None => data.fill(convert(0.0)),
         }
     }
+}
 
-    fn get_max_pattern_len(&self) -> usize {
-        self.tracks.iter().map(|t| t.pattern.len()).max().unwrap_or(1)
+//
+// =========================
+//   E X P O R T
+// =========================
+//
+
+/// Render `loops` full pattern cycles offline and write a 16-bit stereo WAV.
+/// Works on whatever sequencer it's given; callers pass a clone so the
+/// live stream is never touched.
+fn render_to_wav(seq: &mut Sequencer, loops: usize, tail_secs: f32, path: &str) -> io::Result<()> {
+    // with a song playing, one loop is the whole arrangement
+    let steps = loops * seq.song_steps().unwrap_or_else(|| seq.get_max_pattern_len());
+    render_steps_to_wav(seq, steps, tail_secs, path)
+}
+
+/// Render `bars` bars of the current time signature to a WAV.
+fn render_bars_to_wav(seq: &mut Sequencer, bars: usize, tail_secs: f32, path: &str) -> io::Result<()> {
+    let steps = bars * seq.steps_per_bar();
+    render_steps_to_wav(seq, steps, tail_secs, path)
+}
+
+/// The file ends exactly at the last step unless `tail_secs` asks for
+/// more; the tail has no new notes, so only releases, echoes and reverb
+/// ring on.
+fn render_steps_to_wav(seq: &mut Sequencer, steps: usize, tail_secs: f32, path: &str) -> io::Result<()> {
+    seq.metronome = false;
+    seq.rewind();
+    let len = steps * seq.samples_per_step;
+    let mut samples = seq.process_block(len);
+    if tail_secs > 0.0 {
+        seq.set_arrangement(Arrangement::default());
+        seq.tracks.iter_mut().for_each(|t| t.pattern.fill(REST));
+        let tail = (tail_secs * seq.sample_rate) as usize;
+        samples.extend(seq.process_block(tail));
     }
+    write_wav(path, &samples, 2, seq.sample_rate as u32)
+}
 
-    fn trigger_step(&mut self) {
-        for (track_idx, track) in self.tracks.iter().enumerate() {
-            if track.pattern.is_empty() { continue; }
-            
-            let note = track.pattern[self.step % track.pattern.len()];
-            if note < 0 { continue; } // rest
-            
-            let scale_note = self.scale[(note as usize) % self.scale.len()];
-            let midi_base = scale_note + track.transpose + track.octave*12;
+/// 16-bit PCM WAV (RIFF header + data chunk, little-endian) from
+/// interleaved samples.
+fn write_wav(path: &str, samples: &[f32], channels: u16, sample_rate: u32) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let block_align = channels * 2;
+    let mut buf = Vec::with_capacity(44 + data_len as usize);
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&(36 + data_len).to_le_bytes());
+    buf.extend_from_slice(b"WAVE");
+    buf.extend_from_slice(b"fmt ");
+    buf.extend_from_slice(&16u32.to_le_bytes());            // fmt chunk size
+    buf.extend_from_slice(&1u16.to_le_bytes());             // PCM
+    buf.extend_from_slice(&channels.to_le_bytes());
+    buf.extend_from_slice(&sample_rate.to_le_bytes());
+    buf.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes()); // byte rate
+    buf.extend_from_slice(&block_align.to_le_bytes());
+    buf.extend_from_slice(&16u16.to_le_bytes());            // bits per sample
+    buf.extend_from_slice(b"data");
+    buf.extend_from_slice(&data_len.to_le_bytes());
+    for &x in samples {
+        let v = (x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fs::write(path, buf)
+}
+
+/// MIDI ticks per quarter note; divisible by the usual step resolutions
+/// (4 steps per beat gives 24 ticks a step, triplets 32).
+const MIDI_PPQ: u16 = 96;
+
+/// Type-1 Standard MIDI File: a tempo track followed by one track per
+/// `Track`, covering one cycle of the longest pattern.
+fn export_midi(project: &ProjectData, path: &str) -> io::Result<()> {
+    let scale = project.resolved_scale();
+    let steps = project.tracks.iter().map(|t| t.pattern.len()).max().unwrap_or(0);
+    let step_ticks = MIDI_PPQ as u32 / project.steps_per_beat.max(1) as u32;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"MThd");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&(project.tracks.len() as u16 + 1).to_be_bytes());
+    out.extend_from_slice(&MIDI_PPQ.to_be_bytes());
+
+    // tempo track: microseconds per quarter note
+    let tempo = (60_000_000.0 / project.bpm.max(1.0)) as u32;
+    let mut events = vec![0x00, 0xFF, 0x51, 0x03];
+    events.extend_from_slice(&tempo.to_be_bytes()[1..]);
+    write_mtrk(&mut out, events, 0);
+
+    for (channel, track) in project.tracks.iter().enumerate() {
+        let channel = (channel % 16) as u8;
+        let mut events = Vec::new();
+        let name = track.name.as_bytes();
+        events.extend_from_slice(&[0x00, 0xFF, 0x03]);
+        write_vlq(&mut events, name.len() as u32);
+        events.extend_from_slice(name);
 
-            if track_idx < self.voices.len() {
-                for (i, v) in self.voices[track_idx].iter_mut().enumerate() {
-                    let freq = midi_to_freq(midi_base + i as i32 * track.voice_spread);
-                    v.set_frequency(freq);
-                    v.waveform = track.waveform;
-                    v.reset_env();
+        let mut held: Vec<u8> = Vec::new();
+        let mut delta = 0u32;
+        for step in 0..steps {
+            let note = if track.pattern.is_empty() { REST } else { track.pattern[step % track.pattern.len()] };
+            if note != TIE {
+                for &n in &held {
+                    write_vlq(&mut events, delta);
+                    events.extend_from_slice(&[0x80 | channel, n, 0]);
+                    delta = 0;
                 }
+                held.clear();
             }
+            if is_note(note) {
+                let idx = step % track.pattern.len();
+                for midi in track.voice_notes(idx, &scale, track.voice_count()) {
+                    let n = (midi + project.transpose_all).clamp(0, 127) as u8;
+                    if held.contains(&n) { continue; }
+                    write_vlq(&mut events, delta);
+                    let velocity = (100.0 * track.step_velocity(idx)).max(1.0) as u8;
+                    events.extend_from_slice(&[0x90 | channel, n, velocity]);
+                    held.push(n);
+                    delta = 0;
+                }
+            }
+            delta += step_ticks;
         }
+        for &n in &held {
+            write_vlq(&mut events, delta);
+            events.extend_from_slice(&[0x80 | channel, n, 0]);
+            delta = 0;
+        }
+        // end-of-track after any trailing rests so loops line up
+        write_mtrk(&mut out, events, delta);
     }
+    fs::write(path, out)
+}
 
-    pub fn to_project(&self, bpm: f32) -> ProjectData {
-        ProjectData {
-            tracks: self.tracks.clone(),
-            scale: self.scale.clone(),
-            bpm,
-        }
+/// Append an MTrk chunk, closing it with end-of-track `end_delta` ticks
+/// after the last event.
+fn write_mtrk(out: &mut Vec<u8>, mut events: Vec<u8>, end_delta: u32) {
+    write_vlq(&mut events, end_delta);
+    events.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&(events.len() as u32).to_be_bytes());
+    out.extend_from_slice(&events);
+}
+
+/// MIDI variable-length quantity: 7 bits per byte, high bit = more follows.
+fn write_vlq(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
     }
+    out.extend(bytes.iter().rev());
 }
 
 //
 // =========================
-//   A U D I O
+//   P A R S E R
 // =========================
 //
 
-fn play_audio(seq: Arc<Mutex<Sequencer>>) {
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("no output device");
-    let config = device.default_output_config().unwrap();
-    let err_fn = |err| eprintln!("stream error: {err}");
+// guards against macros that use each other in a loop
+const MAX_MACRO_DEPTH: usize = 8;
 
-    let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => {
-            let cfg = config.clone().into();
-            let seq = seq.clone();
-            device.build_output_stream(&cfg, move |data: &mut [f32], _| {
-                if let Ok(mut s) = seq.lock() {
-                    for sample in data { 
-                        *sample = s.process(); 
-                    }
-                }
-            }, err_fn, None).unwrap()
+/// Every `.name(...)` setter a track line may use.
+const SETTERS: &[&str] = &[
+    "o", "trans", "s", "ngate", "lpf", "res", "gain", "db", "detune", "delay", "pan",
+    "adsr", "atk", "rel", "fm", "velenv", "psync", "retrig", "invert", "velcurve", "lfo", "arp",
+    "crush", "glide", "width", "gate", "human",
+];
+
+/// Why a track line was rejected: the offending token and what was wrong
+/// with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub token: String,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(token: &str, message: impl Into<String>) -> Self {
+        Self { token: token.to_string(), message: message.into() }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reject unknown or unclosed `.name(` setters. Quoted text (patterns,
+/// waveform names) is skipped.
+fn check_setters(line: &str) -> Result<(), ParseError> {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        if c == '"' { quoted = !quoted; }
+        if quoted || c != '.' { continue; }
+        let rest = &line[i + 1..];
+        let name_len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        if name_len == 0 || !rest[name_len..].starts_with('(') { continue; }
+        let name = &rest[..name_len];
+        if !SETTERS.contains(&name) {
+            return Err(ParseError::new(name, format!("unknown setter .{}(...)", name)));
         }
-        cpal::SampleFormat::I16 => {
-            let cfg = config.clone().into();
-            let seq = seq.clone();
-            device.build_output_stream(&cfg, move |data: &mut [i16], _| {
-                if let Ok(mut s) = seq.lock() {
-                    for sample in data { 
-                        *sample = (s.process()*i16::MAX as f32) as i16; 
-                    }
-                }
-            }, err_fn, None).unwrap()
+        if !rest[name_len..].contains(')') {
+            return Err(ParseError::new(name, format!("missing ')' after .{}(", name)));
         }
-        cpal::SampleFormat::U16 => {
-            let cfg = config.clone().into();
-            let seq = seq.clone();
-            device.build_output_stream(&cfg, move |data: &mut [u16], _| {
-                if let Ok(mut s) = seq.lock() {
-                    for sample in data {
-                        let v = (s.process()*0.5+0.5).clamp(0.0,1.0);
-                        *sample = (v*u16::MAX as f32) as u16;
-                    }
-                }
-            }, err_fn, None).unwrap()
+    }
+    Ok(())
+}
+
+/// `line` without its `#` comment. A comment starts at the beginning of
+/// the line or after a space, so note names like `c#` and quoted text are
+/// left alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '"' { quoted = !quoted; }
+        if c == '#' && !quoted && prev.is_whitespace() { return &line[..i]; }
+        prev = c;
+    }
+    line
+}
+
+/// A `.vibez` script's commands with their line numbers, skipping blank
+/// and comment-only lines.
+fn read_script(path: &str) -> Result<Vec<(usize, String)>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    Ok(text.lines().enumerate()
+        .map(|(i, line)| (i + 1, strip_comment(line).trim().to_string()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect())
+}
+
+/// Replace every `use <name>` in a track line with that macro's body.
+/// Macros may use other macros.
+fn expand_macros(line: &str, macros: &HashMap<String, String>) -> Result<String, String> {
+    let mut line = line.to_string();
+    // one pass per level, plus one to see the line come out clean
+    for _ in 0..=MAX_MACRO_DEPTH {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if !tokens.contains(&"use") { return Ok(line); }
+        let mut out = Vec::new();
+        let mut iter = tokens.into_iter();
+        while let Some(tok) = iter.next() {
+            if tok != "use" {
+                out.push(tok.to_string());
+                continue;
+            }
+            let name = iter.next().ok_or("expected a macro name after 'use'")?;
+            let body = macros.get(name).ok_or(format!("unknown macro '{name}'"))?;
+            out.push(body.clone());
         }
-        _ => panic!("Unsupported sample format"),
+        line = out.join(" ");
+    }
+    Err("macros nest too deeply (is one using itself?)".to_string())
+}
+
+/// Numeric arguments of a setter such as `.ngate(-40,0.1)`. None if the
+/// setter is absent or any argument fails to parse.
+fn setter_nums(line: &str, setter: &str) -> Result<Option<Vec<f32>>, ParseError> {
+    let Some(open) = line.find(setter).map(|i| i + setter.len()) else { return Ok(None) };
+    let Some(close) = line[open..].find(')').map(|i| i + open) else {
+        return Err(ParseError::new(setter, format!("missing ')' after {}", setter)));
     };
+    line[open..close].split(',')
+        .map(|x| x.trim().parse().map_err(|_| {
+            ParseError::new(x.trim(), format!("expected number in {}...), got '{}'", setter, x.trim()))
+        }))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
 
-    stream.play().unwrap();
-    // Silently run - don't print to console
-    loop { std::thread::sleep(Duration::from_secs(1)); }
+/// Error for a setter given the wrong number of arguments.
+fn arg_count_error(setter: &str, expected: usize, got: usize) -> ParseError {
+    ParseError::new(setter, format!("expected {} numbers in {}...), got {}", expected, setter, got))
 }
 
-//
-// =========================
-//   P A R S E R
-// =========================
-//
+/// Quoted argument of a setter such as `.velcurve("ramp")`.
+fn setter_str<'a>(line: &'a str, setter: &str) -> Option<&'a str> {
+    let open = line.find(setter)? + setter.len();
+    let close = line[open..].find(')')? + open;
+    Some(line[open..close].trim().trim_matches('"'))
+}
 
-fn parse_track_line(line: &str) -> Option<Track> {
-    let mut track = Track::new("Untitled");
+/// A step or chord, `[0,2,4]`, as its list of degrees.
+fn parse_chord(token: &str) -> Option<Vec<i32>> {
+    let Some(inner) = token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) else {
+        return parse_step(token).map(|n| vec![n]);
+    };
+    // rests and ties only make sense for the whole step
+    let notes: Vec<i32> = inner.split(',')
+        .map(|n| parse_step(n.trim()).filter(|&n| is_note(n)))
+        .collect::<Option<_>>()?;
+    if notes.is_empty() { return None; }
+    Some(notes)
+}
+
+/// A step or chord with optional velocity and probability, e.g. `3:0.5`,
+/// `5?0.8` or `[0,2,4]:0.8?0.5`. Both default to 1.0.
+fn parse_pattern_token(token: &str) -> Option<(Vec<i32>, f32, f32)> {
+    let unit = |x: &str| x.parse::<f32>().ok().map(|v| v.clamp(0.0, 1.0));
+    let (rest, prob) = match token.split_once('?') {
+        Some((rest, prob)) => (rest, unit(prob)?),
+        None => (token, 1.0),
+    };
+    let (step, vel) = match rest.split_once(':') {
+        Some((step, vel)) => (step, unit(vel)?),
+        None => (rest, 1.0),
+    };
+    Some((parse_chord(step)?, vel, prob))
+}
+
+/// Split a pattern on whitespace, keeping `[0, 2, 4]` together as one token.
+fn pattern_tokens(pattern: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in pattern.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ => {}
+        }
+        if c.is_whitespace() && depth <= 0 {
+            if !current.is_empty() { tokens.push(std::mem::take(&mut current)); }
+        } else if !c.is_whitespace() {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() { tokens.push(current); }
+    tokens
+}
+
+/// Parse DSL setters on top of `template`, so anything not mentioned in
+/// the line keeps the template's value.
+fn parse_track_line(line: &str, template: &Track) -> Result<Track, ParseError> {
+    check_setters(line)?;
+    let mut track = template.clone();
     
-    // Parse pattern: n"0 3 5 7"
+    // Parse pattern: n"0 3 5 7", with velocities n"0:1.0 3:0.5",
+    // probabilities n"0?0.5 3" and chords n"[0,2,4] 3 5"
     if let Some(start) = line.find("n\"") {
-        if let Some(end_pos) = line[start+2..].find("\"") {
-            let inside = &line[start+2..start+2+end_pos];
-            track.pattern = inside.split_whitespace()
-                .filter_map(|x| x.parse::<i32>().ok())
-                .collect();
-        }
+        let Some(end_pos) = line[start+2..].find("\"") else {
+            return Err(ParseError::new(&line[start..], "unterminated n\"...\" pattern (missing closing quote)"));
+        };
+        let inside = &line[start+2..start+2+end_pos];
+        let steps: Vec<(Vec<i32>, f32, f32)> = pattern_tokens(inside).iter()
+            .map(|t| parse_pattern_token(t).ok_or_else(|| {
+                ParseError::new(t, format!("bad step '{}' in pattern (expected a degree, ~, [chord], :vel or ?prob)", t))
+            }))
+            .collect::<Result<_, _>>()?;
+        track.pattern = steps.iter().map(|(notes, _, _)| notes[0]).collect();
+        track.velocities = if inside.contains(':') {
+            steps.iter().map(|&(_, v, _)| v).collect()
+        } else {
+            Vec::new()
+        };
+        track.probabilities = if inside.contains('?') {
+            steps.iter().map(|&(_, _, p)| p).collect()
+        } else {
+            Vec::new()
+        };
+        track.chords = if inside.contains('[') {
+            steps.iter()
+                .map(|(notes, _, _)| if notes.len() > 1 { notes.clone() } else { Vec::new() })
+                .collect()
+        } else {
+            Vec::new()
+        };
     }
     
     // Parse octave: .o(3)
-    if line.contains(".o(") {
-        if let Some(open) = line.find(".o(") {
-            if let Some(close) = line[open..].find(")") {
-                let val = &line[open+3..open+close];
-                if let Ok(oct) = val.parse() { track.octave = oct; }
-            }
-        }
+    if let Some(open) = line.find(".o(")
+        && let Some(close) = line[open..].find(")")
+    {
+        let val = line[open+3..open+close].trim();
+        track.octave = val.parse().map_err(|_| {
+            ParseError::new(val, format!("expected integer in .o(...), got '{}'", val))
+        })?;
     }
     
     // Parse transpose: .trans(5)
-    if line.contains(".trans(") {
-        if let Some(open) = line.find(".trans(") {
-            if let Some(close) = line[open..].find(")") {
-                let val = &line[open+7..open+close];
-                if let Ok(tr) = val.parse() { track.transpose = tr; }
+    if let Some(open) = line.find(".trans(")
+        && let Some(close) = line[open..].find(")")
+    {
+        let val = line[open+7..open+close].trim();
+        track.transpose = val.parse().map_err(|_| {
+            ParseError::new(val, format!("expected integer in .trans(...), got '{}'", val))
+        })?;
+    }
+    
+    // Parse noise gate: .ngate(-40,0.1) (threshold dB, release s)
+    if let Some(args) = setter_nums(line, ".ngate(")? {
+        track.gate_threshold = args.first().copied();
+        if let Some(&release) = args.get(1) { track.gate_release = release.max(0.0); }
+    }
+
+    // Parse filter: .lpf(800) .res(0.7)
+    if let Some(args) = setter_nums(line, ".lpf(")?
+        && let Some(&cutoff) = args.first()
+    {
+        track.filter_cutoff = cutoff.max(10.0);
+    }
+    if let Some(args) = setter_nums(line, ".res(")?
+        && let Some(&res) = args.first()
+    {
+        track.filter_resonance = res.clamp(0.0, 1.0);
+    }
+
+    // Parse level: .gain(0.5) linear, or .db(-6)
+    if let Some(args) = setter_nums(line, ".gain(")?
+        && let Some(&gain) = args.first()
+    {
+        track.gain = gain.max(0.0);
+    }
+    if let Some(args) = setter_nums(line, ".db(")?
+        && let Some(&db) = args.first()
+    {
+        track.gain = db_to_gain(db);
+    }
+
+    // Parse unison detune in cents: .detune(12)
+    if let Some(args) = setter_nums(line, ".detune(")?
+        && let Some(&cents) = args.first()
+    {
+        track.detune_cents = cents.clamp(0.0, 100.0);
+    }
+
+    // Parse unison stereo width 0..1: .width(0.8)
+    if let Some(args) = setter_nums(line, ".width(")?
+        && let Some(&width) = args.first()
+    {
+        track.unison_width = width.clamp(0.0, 1.0);
+    }
+
+    // Parse echo: .delay(0.375,0.4,0.3) as time, feedback, mix, or .delay(off)
+    if line.contains(".delay(off)") {
+        track.delay_mix = 0.0;
+    } else if let Some(args) = setter_nums(line, ".delay(")? {
+        let [time, feedback, mix] = args[..] else { return Err(arg_count_error(".delay(", 3, args.len())) };
+        track.delay_time = time.clamp(0.0, DELAY_MAX_SECS);
+        track.delay_feedback = feedback.clamp(0.0, DELAY_MAX_FEEDBACK);
+        track.delay_mix = mix.clamp(0.0, 1.0);
+    }
+
+    // Parse bitcrusher: .crush(8,4) as bits, downsample, or .crush(off)
+    if line.contains(".crush(off)") {
+        track.crush_bits = CRUSH_BITS_OFF;
+        track.crush_downsample = 1;
+    } else if let Some(args) = setter_nums(line, ".crush(")? {
+        let [bits, downsample] = args[..] else { return Err(arg_count_error(".crush(", 2, args.len())) };
+        track.crush_bits = (bits as u8).clamp(1, CRUSH_BITS_OFF);
+        track.crush_downsample = (downsample as usize).clamp(1, MAX_CRUSH_DOWNSAMPLE);
+    }
+
+    // Parse portamento: .glide(0.05)
+    if let Some(args) = setter_nums(line, ".glide(")?
+        && let Some(&glide) = args.first()
+    {
+        track.glide = glide.clamp(0.0, MAX_GLIDE);
+    }
+
+    // Parse note length as a fraction of the step: .gate(0.5)
+    if let Some(args) = setter_nums(line, ".gate(")?
+        && let Some(&gate) = args.first()
+    {
+        track.gate = gate.clamp(0.0, 1.0);
+    }
+
+    // Parse humanize amount 0..1: .human(0.2)
+    if let Some(args) = setter_nums(line, ".human(")?
+        && let Some(&amount) = args.first()
+    {
+        track.humanize = amount.clamp(0.0, 1.0);
+    }
+
+    // Parse stereo position: .pan(-0.5)
+    if let Some(args) = setter_nums(line, ".pan(")?
+        && let Some(&pan) = args.first()
+    {
+        track.pan = pan.clamp(-1.0, 1.0);
+    }
+
+    // Parse envelope: .adsr(0.01,0.1,0.5,0.2), or just .atk(0.05) / .rel(0.4)
+    if let Some(args) = setter_nums(line, ".adsr(")? {
+        if args.len() != 4 { return Err(arg_count_error(".adsr(", 4, args.len())); }
+        track.attack = args[0].max(0.0);
+        track.decay = args[1].max(0.0);
+        track.sustain = args[2].clamp(0.0, 1.0);
+        track.release = args[3].max(0.0);
+    }
+    if let Some(args) = setter_nums(line, ".atk(")?
+        && let Some(&attack) = args.first()
+    {
+        track.attack = attack.max(0.0);
+    }
+    if let Some(args) = setter_nums(line, ".rel(")?
+        && let Some(&release) = args.first()
+    {
+        track.release = release.max(0.0);
+    }
+
+    // Parse FM: .fm(2.0,3.0) (ratio, index)
+    if let Some(args) = setter_nums(line, ".fm(")? {
+        if args.len() != 2 { return Err(arg_count_error(".fm(", 2, args.len())); }
+        track.fm_ratio = args[0].max(0.0);
+        track.fm_index = args[1].max(0.0);
+    }
+
+    // Parse velocity-to-envelope amount: .velenv(0.5)
+    if let Some(args) = setter_nums(line, ".velenv(")?
+        && let Some(&amount) = args.first()
+    {
+        track.vel_to_env = amount.clamp(0.0, 1.0);
+    }
+
+    // Parse oscillator phase reset: .retrig(true) / .retrig(false), with
+    // .psync() kept as the older spelling of .retrig(true)
+    if line.contains(".psync()") { track.retrig_phase = true; }
+    if let Some(val) = setter_str(line, ".retrig(") {
+        track.retrig_phase = match val {
+            "true" | "on" => true,
+            "false" | "off" => false,
+            _ => return Err(ParseError::new(val, format!("expected true or false in .retrig(...), got '{}'", val))),
+        };
+    }
+
+    // Parse polarity flip: .invert()
+    if line.contains(".invert()") { track.phase_invert = true; }
+
+    // Parse velocity curve: .velcurve("ramp")
+    if let Some(name) = setter_str(line, ".velcurve(") {
+        match VelocityCurve::from_name(name) {
+            Some(curve) => track.velocity_curve = curve,
+            None => return Err(ParseError::new(name, format!("unknown velocity curve '{}' (flat, ramp, fall, sine)", name))),
+        }
+    }
+
+    // Parse LFO: .lfo(pitch, 5.0, 0.3) with an optional waveform,
+    // .lfo(cutoff, 0.25, 2, "triangle"); .lfo(off) removes it
+    if let Some(args) = setter_str(line, ".lfo(") {
+        let parts: Vec<&str> = args.split(',').map(|p| p.trim().trim_matches('"')).collect();
+        if parts.first() == Some(&"off") {
+            track.lfo = None;
+        } else {
+            let target = parts.first().and_then(|p| LfoTarget::from_name(p));
+            let rate = parts.get(1).and_then(|p| p.parse::<f32>().ok());
+            let depth = parts.get(2).and_then(|p| p.parse::<f32>().ok());
+            match (target, rate, depth) {
+                (Some(target), Some(rate), Some(depth)) => {
+                    let mut lfo = Lfo::new(target, rate, depth);
+                    if let Some(wave) = parts.get(3).and_then(|p| Waveform::from_name(p)).filter(|w| !w.is_noise()) {
+                        lfo.waveform = wave;
+                    }
+                    track.lfo = Some(lfo);
+                }
+                _ => return Err(ParseError::new(args, format!("bad .lfo({}): expected .lfo(pitch|cutoff, rate_hz, depth[, \"wave\"])", args))),
             }
         }
     }
-    
+
+    // Parse arpeggiator: .arp("up", 16) over a chord, .arp(off) removes it
+    if let Some(args) = setter_str(line, ".arp(") {
+        let parts: Vec<&str> = args.split(',').map(|p| p.trim().trim_matches('"')).collect();
+        if parts.first() == Some(&"off") {
+            track.arp = None;
+        } else {
+            let mode = parts.first().and_then(|p| ArpMode::from_name(p));
+            let rate = parts.get(1).map_or(Some(16), |p| p.parse::<usize>().ok()).filter(|&r| r > 0);
+            match (mode, rate) {
+                (Some(mode), Some(rate)) => track.arp = Some(Arp { mode, rate }),
+                _ => return Err(ParseError::new(args, format!("bad .arp({}): expected .arp(up|down|updown|random[, rate])", args))),
+            }
+        }
+    }
+
     // Parse waveform: .s("saw")
-    if line.contains(".s(") {
-        if line.contains("saw") { track.waveform = Waveform::Saw; }
-        else if line.contains("sine") { track.waveform = Waveform::Sine; }
-        else if line.contains("square") { track.waveform = Waveform::Square; }
-        else if line.contains("triangle") { track.waveform = Waveform::Triangle; }
+    // (read only the .s() argument so other quoted setters can't match)
+    if let Some(wave) = setter_str(line, ".s(") {
+        if wave.contains("pink") { track.waveform = Waveform::PinkNoise; }
+        else if wave.contains("noise") { track.waveform = Waveform::Noise; }
+        else if wave.contains("saw") { track.waveform = Waveform::Saw; }
+        else if wave.contains("sine") { track.waveform = Waveform::Sine; }
+        else if wave.contains("square") { track.waveform = Waveform::Square; }
+        else if wave.contains("triangle") { track.waveform = Waveform::Triangle; }
+        else { return Err(ParseError::new(wave, format!("unknown waveform '{}' (sine, saw, square, triangle, noise, pink)", wave))); }
     }
     
-    Some(track)
+    Ok(track)
 }
 
 //
@@ -354,7 +1111,145 @@ fn parse_track_line(line: &str) -> Option<Track> {
 // =========================
 //
 
-fn create_track_interactive(theme: &ColorfulTheme) -> Option<Track> {
+/// State owned by the front end (menu + REPL) rather than the audio engine.
+pub struct Session {
+    /// Starting point for every new track
+    pub template: Track,
+    /// A/B comparison slots keyed by track name
+    pub ab_slots: HashMap<String, Track>,
+    /// DSL snippets defined with `def`, expanded by `use <name>`
+    pub macros: HashMap<String, String>,
+    /// Track snapshots taken before each edit, newest last
+    pub undo: Vec<Vec<Track>>,
+    pub redo: Vec<Vec<Track>>,
+    /// Recent `tap` presses, oldest first
+    pub taps: Vec<Instant>,
+    /// Lines queued by `source` as (file, line number, text), run before
+    /// reading the keyboard again
+    pub script: VecDeque<(String, usize, String)>,
+}
+
+// how many edits `undo` can step back through
+const UNDO_DEPTH: usize = 50;
+
+// lines `source` may have queued at once; a file that sources itself
+// runs into this instead of looping forever
+const MAX_SCRIPT_LINES: usize = 10_000;
+
+// taps kept for the tempo estimate, and the pause that starts a new count
+const TAP_HISTORY: usize = 6;
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tempo from tap times: the median gap, so one late or doubled tap
+/// doesn't drag the result.
+fn tap_bpm(taps: &[Instant]) -> Option<f32> {
+    let mut gaps: Vec<f32> = taps.windows(2)
+        .map(|w| w[1].duration_since(w[0]).as_secs_f32())
+        .collect();
+    if gaps.is_empty() { return None; }
+    gaps.sort_by(f32::total_cmp);
+    let mid = gaps.len() / 2;
+    let median = if gaps.len().is_multiple_of(2) { (gaps[mid - 1] + gaps[mid]) / 2.0 } else { gaps[mid] };
+    (median > 0.0).then(|| 60.0 / median)
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            template: Track::new("Untitled"),
+            ab_slots: HashMap::new(),
+            macros: HashMap::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            taps: Vec::new(),
+            script: VecDeque::new(),
+        }
+    }
+}
+
+impl Session {
+    /// Snapshot the tracks before an edit. A new edit drops the redo history.
+    pub fn checkpoint(&mut self, seq: &SeqHandle) {
+        self.undo.push(seq.read(|s| s.tracks.clone()));
+        if self.undo.len() > UNDO_DEPTH {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+}
+
+fn load_template_file(path: &str) -> Result<Track, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    serde_json::from_str(&json).map_err(|e| format!("{path}: {e}"))
+}
+
+/// Waveform picker, starting on `current`.
+fn select_waveform(theme: &ColorfulTheme, current: Waveform) -> Option<Waveform> {
+    const WAVEFORMS: [(&str, Waveform); 6] = [
+        ("Saw", Waveform::Saw),
+        ("Sine", Waveform::Sine),
+        ("Square", Waveform::Square),
+        ("Triangle", Waveform::Triangle),
+        ("Noise", Waveform::Noise),
+        ("Pink noise", Waveform::PinkNoise),
+    ];
+    let names: Vec<&str> = WAVEFORMS.iter().map(|(name, _)| *name).collect();
+    let idx = Select::with_theme(theme)
+        .with_prompt("Waveform")
+        .default(WAVEFORMS.iter().position(|(_, w)| *w == current).unwrap_or(0))
+        .items(&names)
+        .interact()
+        .ok()?;
+    Some(WAVEFORMS[idx].1)
+}
+
+// jam keys, lowest scale degree first, and the octave jam starts in
+const JAM_KEYS: &str = "asdfghjkl;";
+const JAM_OCTAVE: i32 = 5;
+
+/// Play the current scale from the keyboard: a s d f ... are scale
+/// degrees, z/x shift the octave, Esc goes back.
+fn jam_mode(seq: &SeqHandle, theme: &ColorfulTheme) {
+    let Some(waveform) = select_waveform(theme, Waveform::Saw) else { return };
+    let term = Term::stdout();
+    println!("\n=== Jam ===");
+    println!("  {}  play scale degrees 1-{}", JAM_KEYS, JAM_KEYS.len());
+    println!("  z/x  octave down/up   Esc  back");
+    let mut octave = JAM_OCTAVE;
+    loop {
+        let key = match term.read_key() {
+            Ok(key) => key,
+            Err(e) => {
+                println!("✗ Can't read keys here ({})", e);
+                return;
+            }
+        };
+        match key {
+            Key::Escape => break,
+            Key::Char('z') => {
+                octave = (octave - 1).max(1);
+                println!("  octave {}", octave);
+            }
+            Key::Char('x') => {
+                octave = (octave + 1).min(8);
+                println!("  octave {}", octave);
+            }
+            Key::Char(c) => {
+                let Some(degree) = JAM_KEYS.find(c) else { continue };
+                let midi = seq.read(|s| degree_to_semitone(&s.scale, degree as i32)) + octave * 12;
+                let freq = midi_to_freq(midi);
+                seq.edit(move |s| {
+                    let sample_rate = s.sample_rate;
+                    s.jam.note_on(freq, waveform, sample_rate);
+                });
+            }
+            _ => {}
+        }
+    }
+    println!("✓ Left jam mode");
+}
+
+fn create_track_interactive(theme: &ColorfulTheme, template: &Track) -> Option<Track> {
     println!("\n=== Create New Track ===");
     
     let name: String = Input::with_theme(theme)
@@ -364,71 +1259,144 @@ fn create_track_interactive(theme: &ColorfulTheme) -> Option<Track> {
         .ok()?;
     
     let pattern_str: String = Input::with_theme(theme)
-        .with_prompt("Pattern (space-separated notes, -1 for rest)")
+        .with_prompt("Pattern (space-separated notes, -1 for rest, ~ to tie)")
         .default("0 3 5 7 0 5 3 0".to_string())
         .interact_text()
         .ok()?;
     
     let pattern: Vec<i32> = pattern_str.split_whitespace()
-        .filter_map(|x| x.parse().ok())
+        .filter_map(parse_step)
         .collect();
     
     let octave: i32 = Input::with_theme(theme)
         .with_prompt("Octave")
-        .default(3)
+        .default(template.octave)
         .interact_text()
         .ok()?;
     
     let transpose: i32 = Input::with_theme(theme)
         .with_prompt("Transpose (semitones)")
-        .default(0)
+        .default(template.transpose)
         .interact_text()
         .ok()?;
     
-    let waveforms = vec!["Saw", "Sine", "Square", "Triangle"];
-    let wave_idx = Select::with_theme(theme)
-        .with_prompt("Waveform")
-        .default(0)
-        .items(&waveforms)
-        .interact()
+    let gain: f32 = Input::with_theme(theme)
+        .with_prompt("Gain (linear, 1.0 = unity)")
+        .default(template.gain)
+        .interact_text()
         .ok()?;
     
-    let waveform = match wave_idx {
-        0 => Waveform::Saw,
-        1 => Waveform::Sine,
-        2 => Waveform::Square,
-        3 => Waveform::Triangle,
-        _ => Waveform::Saw,
-    };
+    let waveform = select_waveform(theme, template.waveform)?;
     
-    Some(Track {
-        name,
-        pattern,
-        octave,
-        transpose,
-        waveform,
-        voice_spread: 7,
-    })
+    let mut track = template.clone();
+    track.name = name;
+    track.pattern = pattern;
+    track.octave = octave;
+    track.transpose = transpose;
+    track.waveform = waveform;
+    track.gain = gain.max(0.0);
+    Some(track)
 }
 
-fn save_project(seq: &Arc<Mutex<Sequencer>>, theme: &ColorfulTheme) {
-    let filename: String = Input::with_theme(theme)
+fn save_project(seq: &SeqHandle, theme: &ColorfulTheme) {
+    let Ok(filename) = Input::<String>::with_theme(theme)
         .with_prompt("Save as")
         .default("track.json".to_string())
-        .interact_text()
-        .unwrap();
+        .interact_text() else { return };
     
-    let bpm: f32 = Input::with_theme(theme)
-        .with_prompt("BPM")
-        .default(120.0)
-        .interact_text()
-        .unwrap();
-    
-    if let Ok(s) = seq.lock() {
-        let project = s.to_project(bpm);
-        let json = serde_json::to_string_pretty(&project).unwrap();
-        fs::write(&filename, json).unwrap();
-        println!("✓ Saved to {}", filename);
+    let project = seq.read(Sequencer::to_project);
+    match serialize_project(&project, &filename) {
+        Ok(()) => println!("✓ Saved to {}", filename),
+        Err(e) => println!("✗ Save failed: {}", e),
+    }
+}
+
+/// On-disk project format, picked from the file extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProjectFormat {
+    Json,
+    Ron,
+    Toml,
+}
+
+impl ProjectFormat {
+    /// Format for `path`; unknown extensions fall back to JSON with a warning.
+    fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path).extension().and_then(|e| e.to_str());
+        match ext.map(str::to_lowercase).as_deref() {
+            Some("json") => ProjectFormat::Json,
+            Some("ron") => ProjectFormat::Ron,
+            Some("toml") => ProjectFormat::Toml,
+            other => {
+                println!("⚠ Unknown project extension {:?}, using JSON", other.unwrap_or(""));
+                ProjectFormat::Json
+            }
+        }
+    }
+
+    /// Error for formats this build has no serializer for yet.
+    fn unsupported(self) -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, format!("unsupported format: {:?} projects aren't available in this build", self))
+    }
+}
+
+fn serialize_project(project: &ProjectData, path: &str) -> io::Result<()> {
+    let text = match ProjectFormat::from_path(path) {
+        ProjectFormat::Json => serde_json::to_string_pretty(project).map_err(io::Error::other)?,
+        format => return Err(format.unsupported()),
+    };
+    fs::write(path, text)
+}
+
+fn deserialize_project(path: &str) -> io::Result<ProjectData> {
+    match ProjectFormat::from_path(path) {
+        ProjectFormat::Json => {
+            let text = fs::read_to_string(path)?;
+            serde_json::from_str(&text).map_err(io::Error::other)
+        }
+        format => Err(format.unsupported()),
+    }
+}
+
+fn export_wav(seq: &SeqHandle, theme: &ColorfulTheme) {
+    let Ok(filename) = Input::<String>::with_theme(theme)
+        .with_prompt("Export as")
+        .default("loop.wav".to_string())
+        .interact_text() else { return };
+    let Ok(loops) = Input::<usize>::with_theme(theme)
+        .with_prompt("Number of loops")
+        .default(4)
+        .interact_text() else { return };
+
+    // the file stops at the loop end unless the reverb should ring out
+    let tail = if seq.read(|s| s.reverb[0].wet > 0.0) {
+        let Ok(tail) = Input::<f32>::with_theme(theme)
+            .with_prompt("Reverb tail in seconds (0 = stop at the loop end)")
+            .default(0.0)
+            .interact_text() else { return };
+        tail.max(0.0)
+    } else {
+        0.0
+    };
+
+    // render from a copy so the live stream keeps playing untouched
+    let mut offline = seq.read(Sequencer::clone);
+    match render_to_wav(&mut offline, loops, tail, &filename) {
+        Ok(()) => println!("✓ Exported {} loops to {}", loops, filename),
+        Err(e) => println!("✗ Export failed: {}", e),
+    }
+}
+
+fn export_midi_file(seq: &SeqHandle, theme: &ColorfulTheme) {
+    let Ok(filename) = Input::<String>::with_theme(theme)
+        .with_prompt("Export as")
+        .default("loop.mid".to_string())
+        .interact_text() else { return };
+
+    let project = seq.read(Sequencer::to_project);
+    match export_midi(&project, &filename) {
+        Ok(()) => println!("✓ Exported {} tracks to {}", project.tracks.len(), filename),
+        Err(e) => println!("✗ Export failed: {}", e),
     }
 }
 
@@ -439,35 +1407,259 @@ fn load_project(theme: &ColorfulTheme) -> Option<ProjectData> {
         .interact_text()
         .ok()?;
     
-    let json = fs::read_to_string(&filename).ok()?;
-    let project: ProjectData = serde_json::from_str(&json).ok()?;
+    let project = load_project_file(&filename).ok()?;
     println!("✓ Loaded from {}", filename);
     Some(project)
 }
 
-fn repl_mode(seq: &Arc<Mutex<Sequencer>>) {
+// extension projects (and templates) are saved under
+const PROJECT_EXT: &str = "json";
+
+/// Whether `path` names a project file, going by its extension.
+fn is_project_file(path: &str) -> bool {
+    std::path::Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case(PROJECT_EXT))
+}
+
+/// Project filenames in `dir`, sorted.
+fn list_projects(dir: &str) -> io::Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|n| is_project_file(n))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Parse `intro:4 drop:8 intro:4` into an arrangement of known sections.
+fn parse_arrangement(args: &str, sections: &[Section]) -> Result<Arrangement, String> {
+    let mut arrangement = Arrangement::default();
+    for entry in args.split_whitespace() {
+        let (name, bars) = entry.split_once(':')
+            .ok_or_else(|| format!("'{entry}' should be name:bars"))?;
+        let bars = bars.parse::<usize>().ok().filter(|&b| b > 0)
+            .ok_or_else(|| format!("'{entry}': bars must be a whole number above 0"))?;
+        if !sections.iter().any(|s| s.name == name) {
+            return Err(format!("no section named '{name}'"));
+        }
+        arrangement.sections.push((name.to_string(), bars));
+    }
+    if arrangement.sections.is_empty() {
+        return Err("no sections given".to_string());
+    }
+    Ok(arrangement)
+}
+
+/// The arrangement back in `intro:4 drop:8` form, or `(none)`.
+fn arrangement_text(arrangement: &Arrangement) -> String {
+    if arrangement.sections.is_empty() { return "(none)".to_string(); }
+    arrangement.sections.iter()
+        .map(|(name, bars)| format!("{name}:{bars}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Human-readable differences between two projects, one line each.
+fn diff_projects(a: &ProjectData, b: &ProjectData) -> Vec<String> {
+    let mut out = Vec::new();
+    if a.bpm != b.bpm {
+        out.push(format!("bpm: {} -> {}", a.bpm, b.bpm));
+    }
+    if a.transpose_all != b.transpose_all {
+        out.push(format!("transpose all: {} -> {}", a.transpose_all, b.transpose_all));
+    }
+    if a.swing != b.swing {
+        out.push(format!("swing: {} -> {}", a.swing, b.swing));
+    }
+    if a.master_gain != b.master_gain {
+        out.push(format!("master gain: {} -> {}", a.master_gain, b.master_gain));
+    }
+    if (a.reverb_room, a.reverb_damping, a.reverb_wet) != (b.reverb_room, b.reverb_damping, b.reverb_wet) {
+        out.push(format!("reverb: {} {} {} -> {} {} {}",
+            a.reverb_room, a.reverb_damping, a.reverb_wet, b.reverb_room, b.reverb_damping, b.reverb_wet));
+    }
+    if a.seed != b.seed {
+        out.push(format!("seed: {} -> {}", a.seed, b.seed));
+    }
+    if a.steps_per_beat != b.steps_per_beat {
+        out.push(format!("steps per beat: {} -> {}", a.steps_per_beat, b.steps_per_beat));
+    }
+    if (a.time_sig_num, a.time_sig_den) != (b.time_sig_num, b.time_sig_den) {
+        out.push(format!("time signature: {}/{} -> {}/{}", a.time_sig_num, a.time_sig_den, b.time_sig_num, b.time_sig_den));
+    }
+    if a.scale != b.scale {
+        out.push(format!("scale: {:?} -> {:?}", a.scale, b.scale));
+    }
+    if (&a.mode, &a.root) != (&b.mode, &b.root) {
+        out.push(format!("key: {:?} {:?} -> {:?} {:?}", a.root, a.mode, b.root, b.mode));
+    }
+    if a.arrangement != b.arrangement {
+        out.push(format!("arrangement: {} -> {}",
+            arrangement_text(&a.arrangement), arrangement_text(&b.arrangement)));
+    }
+    for sa in &a.sections {
+        match b.sections.iter().find(|s| s.name == sa.name) {
+            None => out.push(format!("- section '{}' removed", sa.name)),
+            Some(sb) if sb != sa => out.push(format!("~ section '{}' changed", sa.name)),
+            Some(_) => {}
+        }
+    }
+    for sb in &b.sections {
+        if !a.sections.iter().any(|s| s.name == sb.name) {
+            out.push(format!("+ section '{}' added", sb.name));
+        }
+    }
+    for ta in &a.tracks {
+        let Some(tb) = b.tracks.iter().find(|t| t.name == ta.name) else {
+            out.push(format!("- track '{}' removed", ta.name));
+            continue;
+        };
+        // compare field by field through serde so new Track fields are covered
+        let (Ok(serde_json::Value::Object(fa)), Ok(serde_json::Value::Object(fb))) =
+            (serde_json::to_value(ta), serde_json::to_value(tb)) else { continue };
+        for (key, va) in &fa {
+            let vb = fb.get(key).unwrap_or(&serde_json::Value::Null);
+            if va != vb {
+                out.push(format!("~ track '{}' {}: {} -> {}", ta.name, key, va, vb));
+            }
+        }
+    }
+    for tb in &b.tracks {
+        if !a.tracks.iter().any(|t| t.name == tb.name) {
+            out.push(format!("+ track '{}' added", tb.name));
+        }
+    }
+    out
+}
+
+/// Load two project files and print their differences.
+fn print_project_diff(path_a: &str, path_b: &str) {
+    match (load_project_file(path_a), load_project_file(path_b)) {
+        (Ok(a), Ok(b)) => {
+            let lines = diff_projects(&a, &b);
+            if lines.is_empty() {
+                println!("  (no differences)");
+            }
+            for line in lines {
+                println!("  {}", line);
+            }
+        }
+        (Err(e), _) | (_, Err(e)) => println!("✗ Failed to load {}", e),
+    }
+}
+
+/// Known modes, comma-separated, for error messages.
+fn scale_type_names() -> String {
+    SCALE_TYPES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+}
+
+fn load_project_file(path: &str) -> Result<ProjectData, String> {
+    let project = deserialize_project(path).map_err(|e| format!("{path}: {e}"))?;
+    if let Some(mode) = &project.mode
+        && scale_intervals(mode).is_none()
+    {
+        println!("⚠ {}: unknown scale '{}', keeping its saved notes", path, mode);
+    }
+    Ok(project)
+}
+
+fn repl_mode(seq: &SeqHandle, session: &mut Session) {
     println!("\n╔═══════════════════════════════════════════════════════════╗");
     println!("║          R E P L   M O D E                                ║");
     println!("╚═══════════════════════════════════════════════════════════╝");
     println!("Build your track line by line. Each line creates/modifies a track.");
     println!("\nCommands:");
-    println!("  [name] n\"0 3 5 7\" .o(3) .s(\"saw\") .trans(0)");
+    println!("  [name] n\"0 3 5 7\" .o(3) .s(\"saw\") .trans(0) .lpf(800) .res(0.7)");
     println!("  list              - show all tracks");
+    println!("  source <file>     - run each line of a .vibez script as if typed");
+    println!("  # ...             - comment; ignored on its own line or after a command");
     println!("  clear             - remove all tracks");
+    println!("  undo / redo       - step back/forward through track edits");
     println!("  delete <name>     - remove a specific track");
+    println!("  mute/unmute <name> - silence a track (saved)");
+    println!("  solo/unsolo <name> - hear only soloed tracks");
+    println!("  meters            - show peak/RMS level per track");
+    println!("  stats             - active voices, output peak and buffer underruns");
+    println!("  panic             - silence all voices now");
+    println!("  jam               - play the scale from the keyboard (Esc to stop)");
+    println!("  scale             - show the current scale's notes");
+    println!("  scale <mode> <root> - major, minor, dorian, phrygian, lydian, mixolydian, locrian");
+    println!("  root <note>       - move the scale to a new root and preview it");
+    println!("  mode <mode>       - change scale type on the same root; patterns follow");
+    println!("  transpose_all <n> - shift every track by n semitones (0 = off)");
+    println!("  detect scale      - suggest the scale that fits the notes in use");
+    println!("  click on|off      - metronome (accented on the downbeat)");
+    println!("  master <gain>     - master level into the limiter (1 = unity)");
+    println!("  mastercut <hz|off> - master high-cut filter");
+    println!("  reverb <room> <damp> <wet> | off - master reverb, e.g. reverb 0.7 0.5 0.3");
+    println!("  freqclamp <min> <max> - keep every voice between these Hz");
+    println!("  stutter <name> <steps> - repeat the last steps for a bar");
+    println!("  swingrange <name> <start> <end> <amount> - swing off-beats in a step range");
+    println!("  ab store <name>   - keep the track's current state in its B slot");
+    println!("  ab toggle <name>  - swap the track with its B slot");
+    println!("  def <macro> <setters...> - define a reusable snippet, e.g. def pluck .s(\"saw\") .o(4)");
+    println!("  <name> n\"...\" use <macro>  - expand a snippet inside a track line");
+    println!("  template [file.json | .o(2) .s(\"sine\") ...] - show/set new-track defaults");
+    println!("  drift <cents>     - analog pitch drift (0 = off)");
+    println!("  declick <ms>      - smoothing for live waveform changes (0 = off)");
+    println!("  autoaccent <amt>  - boost steps on the beat (0 = off)");
+    println!("  bpm <n>           - change tempo live");
+    println!("  tap               - enter repeatedly in time to set the tempo");
+    println!("  ramp <from> <to> <bars> - glide the tempo bar by bar");
+    println!("  div <n>           - steps per beat (4 = 16ths, 3 = triplets)");
+    println!("  swing <amt>       - delay off-beat steps (0 = straight, max 0.75)");
+    println!("  timesig <n>/<d>   - time signature for beats and bars (e.g. 7/8)");
+    println!("  section <name>    - save the current patterns as a song section");
+    println!("  sections          - list sections and the arrangement");
+    println!("  arrange <name:bars>... - play sections in order, e.g. intro:4 drop:8 (off = loop)");
+    println!("  combine <a> <b> <dst> - overlay two patterns into a new track");
+    println!("  reverse <name>    - play a track's pattern backwards");
+    println!("  rotate <name> <n> - shift a pattern n steps later (negative = earlier)");
+    println!("  invert <name>     - mirror a pattern's notes within the scale");
+    println!("  bassfrom <src> <dst>  - bass line on the roots of a chord track");
+    println!("  random <name> [steps] - generate a track in the current scale");
+    println!("  seed [n]          - show/set the random seed (saved; a new seed reshuffles");
+    println!("                      probabilistic steps, noise and random tracks)");
+    println!("  progression <name> I V vi IV - one triad per bar from roman numerals");
+    println!("  harmonize <name> <interval> - add a harmony a scale interval away (3, 5, -3...)");
+    println!("  at <bar>:<beat> <bpm|mastercut|drift> <value> - schedule a change");
+    println!("  browse <dir>      - pick a saved project from a directory");
+    println!("  diff <a.json> <b.json> - compare two saved projects");
     println!("  exit              - return to main menu");
     println!("\nExample:");
-    println!("  bass n\"0 0 -1 0\" .o(2) .s(\"sine\")");
-    println!("  lead n\"0 3 5 7 5 3\" .o(4) .s(\"saw\") .trans(5)");
-    println!("  pad n\"0 2 4\" .o(3) .s(\"triangle\")\n");
+    println!("  bass n\"0 0 -1 0\" .o(2) .s(\"sine\") .db(-3)");
+    println!("  lead n\"0 3 5 7 5 3\" .o(4) .s(\"saw\") .trans(5) .velcurve(\"ramp\")");
+    println!("  pad n\"0 2 4\" .o(3) .s(\"triangle\") .ngate(-40,0.1)");
+    println!("  bell n\"0 4 7\" .o(4) .s(\"sine\") .fm(3.5,2.0)");
+    println!("  pad2 n\"0 ~ ~ ~\" .o(3) .adsr(0.4,0.2,0.7,1.0)   (or .atk(0.05) .rel(0.5))");
+    println!("  saw n\"0 3 5\" .o(4) .detune(12) .width(0.8)   (supersaw unison: cents, stereo width)");
+    println!("  lead n\"0 ~ 4 ~\" .delay(0.375,0.4,0.3)   (echo: time, feedback, mix)");
+    println!("  grit n\"0 0 3 0\" .o(2) .crush(6,4)   (bitcrush: bits, downsample)");
+    println!("  acid n\"0 ~ 3 5 ~ 0\" .o(2) .glide(0.05)   (slide between held notes)");
+    println!("  stab n\"0 0 ~ 3\" .o(4) .gate(0.3)   (release after 30% of each note)");
+    println!("  keys n\"0 2 4 2\" .o(4) .human(0.3)   (loosen timing and velocity)");
+    println!("  pad n\"0 3 5\" .s(\"square\") .retrig(false)   (let the oscillator run on between notes)");
+    println!("  arp n\"[0,2,4] ~ ~ ~\" .o(4) .arp(\"updown\", 16)   (arpeggiate held chords)");
+    println!("  vib n\"0 2 4\" .o(4) .lfo(pitch, 5.0, 0.3)   (depth: semitones, or octaves for cutoff)");
+    println!("  hats n\"0 0 0 0\" .s(\"noise\") .lpf(9000) .pan(0.4)   (noise or \"pink\"; pan -1..1)");
+    println!("  hold n\"0 ~ ~ 3\" .o(3) .s(\"saw\")   (~ ties the previous note)");
+    println!("  acc n\"0:1.0 3:0.5 5:0.8\" .o(3)  (step:velocity, 0..1)");
+    println!("  gen n\"0?0.5 3 5?0.8\" .o(4)  (step?probability, 0..1)");
+    println!("  keys n\"[0,2,4] -1 [3,5,7] -1\" .o(4) .s(\"triangle\")  (chords)\n");
 
     loop {
-        print!("repl> ");
-        io::stdout().flush().unwrap();
-        
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
-        let input = input.trim();
+        // sourced lines run first, as if typed; `at` locates their errors
+        let (line, at) = match session.script.pop_front() {
+            Some((file, n, line)) => (line, format!("{file}:{n}: ")),
+            None => {
+                print!("repl> ");
+                io::stdout().flush().unwrap();
+                let mut line = String::new();
+                io::stdin().read_line(&mut line).unwrap();
+                (line, String::new())
+            }
+        };
+        let input = strip_comment(&line).trim();
         
         if input.is_empty() { continue; }
         
@@ -476,65 +1668,705 @@ fn repl_mode(seq: &Arc<Mutex<Sequencer>>) {
                 println!("Exiting REPL mode...");
                 break;
             }
-            "list" => {
-                if let Ok(s) = seq.lock() {
-                    if s.tracks.is_empty() {
-                        println!("  (no tracks)");
-                    } else {
-                        println!("\n=== Current Tracks ===");
-                        for (idx, track) in s.tracks.iter().enumerate() {
-                            println!("  {}. {} - Pattern: {:?}, O:{}, T:{}, W:{:?}", 
-                                idx + 1, track.name, track.pattern, 
-                                track.octave, track.transpose, track.waveform);
+            "list" => seq.read(|s| {
+                if s.tracks.is_empty() {
+                    println!("  (no tracks)");
+                } else {
+                    println!("\n=== Current Tracks ===");
+                    for (idx, track) in s.tracks.iter().enumerate() {
+                        let flags = format!("{}{}",
+                            if track.muted { " [M]" } else { "" },
+                            if track.soloed { " [S]" } else { "" });
+                        println!("  {}. {}{} - Pattern: {}, O:{}, T:{}, W:{:?}", 
+                            idx + 1, track.name, flags, pattern_text(&track.pattern), 
+                            track.octave, track.transpose, track.waveform);
+                    }
+                }
+            }),
+            "scale" => seq.read(|s| {
+                let names: Vec<String> = s.scale.iter().map(|&n| semitone_to_note(n)).collect();
+                match &s.scale_name {
+                    Some((mode, root)) => println!("  Scale: {} {} - {}", root.to_uppercase(), mode, names.join(" ")),
+                    None => println!("  Scale: {}", names.join(" ")),
+                }
+            }),
+            _ if input.starts_with("scale ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                if args.len() != 2 {
+                    println!("✗ Usage: scale <mode> <root>  (e.g. scale dorian d)");
+                    continue;
+                }
+                let (mode, root) = (args[0].to_string(), args[1].to_string());
+                if scale_intervals(&mode).is_none() {
+                    println!("✗ Unknown mode '{}'. Try: {}", mode, scale_type_names());
+                    continue;
+                }
+                let scale = seq.change_scale(|s| s.set_scale(&mode, &root));
+                let names: Vec<String> = scale.iter().map(|&n| semitone_to_note(n)).collect();
+                println!("✓ Scale: {}", names.join(" "));
+            }
+            "detect scale" => {
+                let notes = seq.read(Sequencer::used_notes);
+                if notes.is_empty() {
+                    println!("  (no notes to analyse)");
+                } else {
+                    let (name, root) = best_fit_scale(&notes);
+                    println!("  Best fit: {} {}", root, name);
+                }
+            }
+            "jam" => {
+                jam_mode(seq, &ColorfulTheme::default());
+            }
+            "panic" => {
+                seq.edit(Sequencer::panic);
+                println!("✓ All voices silenced");
+            }
+            "meters" => {
+                let meters = seq.meters();
+                seq.read(|s| {
+                    if s.tracks.is_empty() {
+                        println!("  (no tracks)");
+                    }
+                    for (track, meter) in s.tracks.iter().zip(&meters) {
+                        let peak_db = to_db(meter.peak);
+                        // 40 columns spanning -60..0 dBFS
+                        let width = (((peak_db + 60.0) / 60.0).clamp(0.0, 1.0) * 40.0) as usize;
+                        println!("  {:<12} |{:<40}| peak {:6.1} dB  rms {:6.1} dB",
+                            track.name, "#".repeat(width), peak_db, to_db(meter.rms()));
+                    }
+                });
+            }
+            "stats" => {
+                let stats = seq.stats();
+                println!("  voices     {}", stats.active_voices());
+                println!("  peak       {:.2} ({:.1} dBFS)", stats.peak(), to_db(stats.peak()));
+                println!("  underruns  {}", stats.underruns());
+            }
+            "undo" | "redo" => {
+                let (from, to) = if input == "undo" {
+                    (&mut session.undo, &mut session.redo)
+                } else {
+                    (&mut session.redo, &mut session.undo)
+                };
+                match from.pop() {
+                    Some(tracks) => {
+                        to.push(seq.read(|s| s.tracks.clone()));
+                        let count = tracks.len();
+                        seq.restore_tracks(tracks);
+                        println!("✓ {} ({} tracks)", if input == "undo" { "Undone" } else { "Redone" }, count);
+                    }
+                    None => println!("✗ Nothing to {}", input),
+                }
+            }
+            "clear" => {
+                session.checkpoint(seq);
+                seq.clear_tracks();
+                println!("✓ All tracks cleared");
+            }
+            _ if input.starts_with("at ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                let pos = args.first().and_then(|p| p.split_once(':'))
+                    .and_then(|(b, t)| Some((b.parse::<usize>().ok()?, t.parse::<usize>().ok()?)));
+                let value = args.get(2).and_then(|v| v.parse::<f32>().ok());
+                let change = match (args.get(1).copied(), value) {
+                    (Some("bpm"), Some(v)) if v > 0.0 => Some(ParamChange::Bpm(v)),
+                    (Some("mastercut"), Some(v)) if v >= 0.0 => Some(ParamChange::MasterCut(v)),
+                    (Some("drift"), Some(v)) if v >= 0.0 => Some(ParamChange::Drift(v)),
+                    _ => None,
+                };
+                match (pos, change) {
+                    (Some((bar, beat)), Some(change)) if bar > 0 && beat > 0 => {
+                        println!("✓ Scheduled {:?} at {}:{}", change, bar, beat);
+                        seq.edit(move |s| s.schedule_change(bar, beat, change.clone()));
+                    }
+                    _ => println!("✗ Usage: at <bar>:<beat> <bpm|mastercut|drift> <value>  (e.g. at 8:1 bpm 140)"),
+                }
+            }
+            _ if input.starts_with("diff ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                if args.len() != 2 {
+                    println!("✗ Usage: diff <fileA> <fileB>");
+                    continue;
+                }
+                print_project_diff(args[0], args[1]);
+            }
+            _ if input.starts_with("browse ") => {
+                let dir = input.strip_prefix("browse ").unwrap().trim();
+                let projects = match list_projects(dir) {
+                    Ok(p) if !p.is_empty() => p,
+                    Ok(_) => { println!("  (no .{} projects in {})", PROJECT_EXT, dir); continue; }
+                    Err(e) => { println!("✗ {}: {}", dir, e); continue; }
+                };
+                for (idx, name) in projects.iter().enumerate() {
+                    println!("  {}. {}", idx + 1, name);
+                }
+                print!("load #> ");
+                io::stdout().flush().unwrap();
+                let mut choice = String::new();
+                io::stdin().read_line(&mut choice).unwrap();
+                let Some(name) = choice.trim().parse::<usize>().ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|i| projects.get(i))
+                else {
+                    println!("✗ No project loaded");
+                    continue;
+                };
+                let path = std::path::Path::new(dir).join(name);
+                match load_project_file(&path.to_string_lossy()) {
+                    Ok(project) => {
+                        session.checkpoint(seq);
+                        seq.replace_project(project);
+                        println!("✓ Loaded {}", name);
+                    }
+                    Err(e) => println!("✗ Failed to load {}", e),
+                }
+            }
+            _ if input.starts_with("root ") => {
+                let root = input.strip_prefix("root ").unwrap().trim().to_string();
+                let scale = seq.change_scale(|s| s.set_root(&root));
+                seq.audition_scale();
+                let names: Vec<String> = scale.iter().map(|&n| semitone_to_note(n)).collect();
+                println!("✓ Scale: {}", names.join(" "));
+            }
+            _ if input.starts_with("mode ") => {
+                let mode = input.strip_prefix("mode ").unwrap().trim().to_string();
+                if scale_intervals(&mode).is_none() {
+                    println!("✗ Unknown mode '{}'. Try: {}", mode, scale_type_names());
+                    continue;
+                }
+                let scale = seq.change_scale(|s| s.set_mode(&mode));
+                let names: Vec<String> = scale.iter().map(|&n| semitone_to_note(n)).collect();
+                println!("✓ Scale: {}", names.join(" "));
+            }
+            _ if input.starts_with("transpose_all ") => {
+                let arg = input.strip_prefix("transpose_all ").unwrap().trim();
+                match arg.parse::<i32>() {
+                    Ok(semis) if semis.abs() <= MAX_TRANSPOSE_ALL => {
+                        seq.edit(move |s| s.transpose_all = semis);
+                        println!("✓ All tracks transposed by {} semitones", semis);
+                    }
+                    _ => println!("✗ Usage: transpose_all <-{0}..{0}>  (semitones, e.g. 12 = up an octave)", MAX_TRANSPOSE_ALL),
+                }
+            }
+            _ if input.starts_with("timesig ") => {
+                let arg = input.strip_prefix("timesig ").unwrap().trim();
+                let parsed = arg.split_once('/')
+                    .and_then(|(n, d)| Some((n.parse::<usize>().ok()?, d.parse::<usize>().ok()?)));
+                match parsed {
+                    Some((num, den)) if num > 0 && den > 0 => {
+                        let per_beat = seq.read(|s| s.steps_per_beat);
+                        if !Sequencer::fits_time_signature(per_beat, den) {
+                            println!("✗ A 1/{} beat isn't a whole number of steps at div {}", den, per_beat);
+                            continue;
                         }
+                        let steps = seq.edit(move |s| { s.set_time_signature(num, den); s.steps_per_bar() });
+                        println!("✓ Time signature {}/{} ({} steps per bar)", num, den, steps);
                     }
+                    _ => println!("✗ Usage: timesig <num>/<den>  (e.g. timesig 7/8)"),
                 }
             }
-            "clear" => {
-                if let Ok(mut s) = seq.lock() {
-                    s.tracks.clear();
-                    s.voices.clear();
-                    println!("✓ All tracks cleared");
+            _ if input.starts_with("autoaccent ") => {
+                let arg = input.strip_prefix("autoaccent ").unwrap().trim();
+                match arg.parse::<f32>() {
+                    Ok(amount) if amount >= 0.0 => {
+                        seq.edit(move |s| s.auto_accent = amount);
+                        println!("✓ Auto-accent set to {}", amount);
+                    }
+                    _ => println!("✗ Usage: autoaccent <amount>  (e.g. autoaccent 0.5)"),
+                }
+            }
+            _ if input.starts_with("bpm ") => {
+                let arg = input.strip_prefix("bpm ").unwrap().trim();
+                match arg.parse::<f32>() {
+                    Ok(bpm) if bpm > 0.0 && bpm.is_finite() => {
+                        seq.set_bpm(bpm);
+                        println!("✓ Tempo set to {} BPM", bpm);
+                    }
+                    _ => println!("✗ BPM must be a positive number (e.g. bpm 140)"),
+                }
+            }
+            "seed" => {
+                println!("  seed {}", seq.read(|s| s.seed));
+            }
+            _ if input.starts_with("seed ") => {
+                match input.strip_prefix("seed ").unwrap().trim().parse::<u64>() {
+                    Ok(seed) => {
+                        seq.edit(move |s| s.reseed(seed));
+                        println!("✓ Seed set to {} (probabilistic steps and random tracks reshuffle)", seed);
+                    }
+                    Err(_) => println!("✗ Usage: seed <whole number>"),
+                }
+            }
+            "tap" => {
+                let now = Instant::now();
+                if session.taps.last().is_some_and(|&t| now.duration_since(t) > TAP_TIMEOUT) {
+                    session.taps.clear();
+                }
+                session.taps.push(now);
+                if session.taps.len() > TAP_HISTORY {
+                    session.taps.remove(0);
+                }
+                match tap_bpm(&session.taps) {
+                    Some(bpm) => {
+                        let bpm = bpm.round();
+                        seq.set_bpm(bpm);
+                        println!("✓ Tempo set to {} BPM ({} taps)", bpm, session.taps.len());
+                    }
+                    None => println!("  tap again to set the tempo"),
+                }
+            }
+            _ if input.starts_with("source ") => {
+                let path = input.strip_prefix("source ").unwrap().trim();
+                match read_script(path) {
+                    Ok(lines) if session.script.len() + lines.len() > MAX_SCRIPT_LINES => {
+                        println!("✗ {}: too many lines queued (does it source itself?)", path);
+                    }
+                    Ok(lines) => {
+                        println!("✓ Running {} lines from {}", lines.len(), path);
+                        // ahead of anything already queued, so nested sources run in place
+                        for (n, line) in lines.into_iter().rev() {
+                            session.script.push_front((path.to_string(), n, line));
+                        }
+                    }
+                    Err(e) => println!("✗ {}", e),
+                }
+            }
+            _ if input.starts_with("ramp ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                let nums = (
+                    args.first().and_then(|x| x.parse::<f32>().ok()),
+                    args.get(1).and_then(|x| x.parse::<f32>().ok()),
+                    args.get(2).and_then(|x| x.parse::<usize>().ok()),
+                );
+                match nums {
+                    (Some(from), Some(to), Some(bars)) if args.len() == 3 && from > 0.0 && to > 0.0 && bars > 0 => {
+                        seq.edit(move |s| s.start_ramp(from, to, bars));
+                        println!("✓ Ramping {} → {} BPM over {} bars", from, to, bars);
+                    }
+                    _ => println!("✗ Usage: ramp <from_bpm> <to_bpm> <bars>"),
+                }
+            }
+            _ if input.starts_with("section ") => {
+                let name = input.strip_prefix("section ").unwrap().trim().to_string();
+                if name.is_empty() || name.contains(':') || name.contains(char::is_whitespace) {
+                    println!("✗ Usage: section <name>  (one word, no ':')");
+                } else {
+                    let count = seq.read(|s| s.tracks.len());
+                    seq.save_section(&name);
+                    println!("✓ Section '{}' saved ({} tracks)", name, count);
+                }
+            }
+            "sections" => seq.read(|s| {
+                if s.sections.is_empty() {
+                    println!("  (no sections; save one with 'section <name>')");
+                }
+                for section in &s.sections {
+                    let tracks: Vec<&str> = section.parts.keys().map(String::as_str).collect();
+                    println!("  {:<10} {}", section.name, tracks.join(", "));
+                }
+                println!("  arrangement: {}", arrangement_text(&s.arrangement));
+            }),
+            _ if input.starts_with("arrange ") => {
+                let args = input.strip_prefix("arrange ").unwrap().trim();
+                if args == "off" {
+                    seq.set_arrangement(Arrangement::default());
+                    println!("✓ Arrangement off; looping the current patterns");
+                } else {
+                    match seq.read(|s| parse_arrangement(args, &s.sections)) {
+                        Ok(arrangement) => {
+                            let bars = arrangement.total_bars();
+                            seq.set_arrangement(arrangement);
+                            println!("✓ Song of {} bars starts at the next bar line", bars);
+                        }
+                        Err(e) => println!("✗ {}", e),
+                    }
+                }
+            }
+            _ if input.starts_with("div ") => {
+                let arg = input.strip_prefix("div ").unwrap().trim();
+                match arg.parse::<usize>() {
+                    Ok(steps) if (1..=MAX_STEPS_PER_BEAT).contains(&steps) => {
+                        let den = seq.read(|s| s.time_sig_den);
+                        if !Sequencer::fits_time_signature(steps, den) {
+                            println!("✗ {} steps per beat can't make a 1/{} beat; change timesig first", steps, den);
+                            continue;
+                        }
+                        let bar = seq.edit(move |s| { s.set_steps_per_beat(steps); s.steps_per_bar() });
+                        println!("✓ {} steps per beat ({} steps per bar)", steps, bar);
+                    }
+                    _ => println!("✗ Usage: div <1..{}>  (4 = 16ths, 3 = triplets)", MAX_STEPS_PER_BEAT),
+                }
+            }
+            _ if input.starts_with("swing ") => {
+                let arg = input.strip_prefix("swing ").unwrap().trim();
+                match arg.parse::<f32>() {
+                    Ok(amount) if (0.0..=MAX_SWING).contains(&amount) => {
+                        seq.edit(move |s| s.set_swing(amount));
+                        println!("✓ Swing set to {}", amount);
+                    }
+                    _ => println!("✗ Usage: swing <0..{}>  (e.g. swing 0.2)", MAX_SWING),
+                }
+            }
+            _ if input.starts_with("drift ") => {
+                let arg = input.strip_prefix("drift ").unwrap().trim();
+                match arg.parse::<f32>() {
+                    Ok(cents) if cents >= 0.0 => {
+                        seq.edit(move |s| s.analog_drift = cents);
+                        println!("✓ Analog drift set to {} cents", cents);
+                    }
+                    _ => println!("✗ Usage: drift <cents>  (e.g. drift 3)"),
+                }
+            }
+            _ if input.starts_with("declick ") => {
+                let arg = input.strip_prefix("declick ").unwrap().trim();
+                let max_ms = MAX_DECLICK * 1000.0;
+                match arg.parse::<f32>() {
+                    Ok(ms) if (0.0..=max_ms).contains(&ms) => {
+                        seq.edit(move |s| s.declick = ms / 1000.0);
+                        println!("✓ Declick set to {} ms", ms);
+                    }
+                    _ => println!("✗ Usage: declick <0..{}>  (milliseconds, default {})", max_ms, DEFAULT_DECLICK * 1000.0),
+                }
+            }
+            _ if input.starts_with("freqclamp ") => {
+                let args: Vec<f32> = input.split_whitespace().skip(1)
+                    .filter_map(|x| x.parse().ok())
+                    .collect();
+                if args.len() != 2 {
+                    println!("✗ Usage: freqclamp <min> <max>  (e.g. freqclamp 20 10000)");
+                    continue;
+                }
+                let (min, max) = (args[0], args[1]);
+                let (lo, hi) = seq.edit(move |s| { s.set_freq_clamp(min, max); (s.freq_min, s.freq_max) });
+                println!("✓ Voices clamped to {:.1}..{:.1} Hz", lo, hi);
+            }
+            "click on" | "click off" => {
+                let on = input == "click on";
+                seq.edit(move |s| s.metronome = on);
+                println!("✓ Metronome {}", if on { "on" } else { "off" });
+            }
+            _ if input.starts_with("reverb ") => {
+                let arg = input.strip_prefix("reverb ").unwrap().trim();
+                let nums: Option<Vec<f32>> = arg.split_whitespace().map(|x| x.parse().ok()).collect();
+                match (arg, nums.as_deref()) {
+                    ("off", _) => {
+                        seq.edit(|s| s.reverb.iter_mut().for_each(|r| r.wet = 0.0));
+                        println!("✓ Reverb off");
+                    }
+                    (_, Some(&[room, damping, wet])) => {
+                        seq.edit(move |s| s.set_reverb(room, damping, wet));
+                        println!("✓ Reverb room {} damping {} wet {}", room, damping, wet);
+                    }
+                    _ => println!("✗ Usage: reverb <room 0-1> <damping 0-1> <wet 0-1> | reverb off"),
+                }
+            }
+            _ if input.starts_with("master ") => {
+                let arg = input.strip_prefix("master ").unwrap().trim();
+                match arg.parse::<f32>() {
+                    Ok(gain) if gain >= 0.0 => {
+                        seq.edit(move |s| s.master.gain = gain);
+                        println!("✓ Master gain {} ({:.1} dB)", gain, to_db(gain));
+                    }
+                    _ => println!("✗ Usage: master <gain>  (e.g. master 0.8)"),
+                }
+            }
+            _ if input.starts_with("mastercut ") => {
+                let arg = input.strip_prefix("mastercut ").unwrap().trim();
+                let hz = if arg == "off" { Ok(0.0) } else { arg.parse::<f32>() };
+                match hz {
+                    Ok(hz) if hz >= 0.0 => {
+                        seq.edit(move |s| s.set_master_cut(hz));
+                        if hz > 0.0 {
+                            println!("✓ Master high-cut at {} Hz", hz);
+                        } else {
+                            println!("✓ Master high-cut off");
+                        }
+                    }
+                    _ => println!("✗ Usage: mastercut <hz>  or  mastercut off"),
+                }
+            }
+            _ if input.starts_with("ab ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                if args.len() != 2 {
+                    println!("✗ Usage: ab store <name>  |  ab toggle <name>");
+                    continue;
+                }
+                let name = args[1];
+                let Some(live) = seq.read(|s| s.tracks.iter().find(|t| t.name == name).cloned()) else {
+                    println!("✗ Track '{}' not found", name);
+                    continue;
+                };
+                match args[0] {
+                    "store" => {
+                        session.ab_slots.insert(name.to_string(), live);
+                        println!("✓ Stored '{}' in slot B", name);
+                    }
+                    "toggle" => {
+                        if let Some(stored) = session.ab_slots.remove(name) {
+                            session.checkpoint(seq);
+                            session.ab_slots.insert(name.to_string(), live);
+                            seq.upsert_track(stored);
+                            println!("✓ Swapped '{}' with slot B", name);
+                        } else {
+                            println!("✗ Nothing stored for '{}' (use ab store first)", name);
+                        }
+                    }
+                    _ => println!("✗ Usage: ab store <name>  |  ab toggle <name>"),
+                }
+            }
+            _ if input.starts_with("def ") => {
+                let Some((name, body)) = input.strip_prefix("def ").unwrap().trim().split_once(' ') else {
+                    println!("✗ Usage: def <macro> <setters...>");
+                    continue;
+                };
+                session.macros.insert(name.to_string(), body.trim().to_string());
+                println!("✓ Defined macro '{}'", name);
+            }
+            "template" => {
+                let t = &session.template;
+                println!("  Template: O:{}, T:{}, W:{:?}, spread:{}",
+                    t.octave, t.transpose, t.waveform, t.voice_spread);
+            }
+            _ if input.starts_with("template ") => {
+                let arg = input.strip_prefix("template ").unwrap().trim();
+                if is_project_file(arg) {
+                    match load_template_file(arg) {
+                        Ok(template) => {
+                            session.template = template;
+                            println!("✓ Template loaded from {}", arg);
+                        }
+                        Err(e) => println!("✗ Failed to load template {}", e),
+                    }
+                } else {
+                    match parse_track_line(arg, &session.template) {
+                        Ok(template) => {
+                            session.template = template;
+                            println!("✓ Template updated");
+                        }
+                        Err(e) => println!("✗ {}", e),
+                    }
+                }
+            }
+            _ if input.starts_with("swingrange ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                let nums = (
+                    args.get(1).and_then(|x| x.parse::<usize>().ok()),
+                    args.get(2).and_then(|x| x.parse::<usize>().ok()),
+                    args.get(3).and_then(|x| x.parse::<f32>().ok()),
+                );
+                let (Some(name), (Some(start), Some(end), Some(amount))) = (args.first(), nums) else {
+                    println!("✗ Usage: swingrange <name> <start> <end> <amount>");
+                    continue;
+                };
+                let target = name.to_string();
+                let found = seq.edit(move |s| {
+                    s.tracks.iter_mut().find(|t| t.name == target)
+                        .map(|track| track.swing_range(start, end, amount))
+                        .is_some()
+                });
+                if found {
+                    println!("✓ Swing {} on '{}' steps {}..={}", amount, name, start, end);
+                } else {
+                    println!("✗ Track '{}' not found", name);
+                }
+            }
+            _ if input.starts_with("stutter ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                let steps = args.get(1).and_then(|n| n.parse::<usize>().ok());
+                match (args.first(), steps) {
+                    (Some(name), Some(steps)) if steps > 0 => {
+                        if let Some(idx) = seq.read(|s| s.tracks.iter().position(|t| t.name == *name)) {
+                            seq.edit(move |s| s.stutter(idx, steps));
+                            println!("✓ Stuttering '{}' ({} steps)", name, steps);
+                        } else {
+                            println!("✗ Track '{}' not found", name);
+                        }
+                    }
+                    _ => println!("✗ Usage: stutter <name> <steps>"),
+                }
+            }
+            _ if ["reverse ", "rotate ", "invert "].iter().any(|c| input.starts_with(c)) => {
+                let args: Vec<&str> = input.split_whitespace().collect();
+                let shift = match (args[0], args.get(2)) {
+                    ("rotate", Some(n)) => n.parse::<isize>().ok(),
+                    ("rotate", None) => None,
+                    _ => Some(0),
+                };
+                let expected = if args[0] == "rotate" { 3 } else { 2 };
+                let (Some(shift), true) = (shift, args.len() == expected) else {
+                    println!("✗ Usage: reverse <name> | rotate <name> <steps> | invert <name>");
+                    continue;
+                };
+                let (found, scale_len) = seq.read(|s| (
+                    s.tracks.iter().find(|t| t.name == args[1]).cloned(),
+                    s.scale.len(),
+                ));
+                let Some(mut track) = found else {
+                    println!("✗ Track '{}' not found", args[1]);
+                    continue;
+                };
+                match args[0] {
+                    "reverse" => track.reverse(),
+                    "rotate" => track.rotate(shift),
+                    _ => track.invert(scale_len),
+                }
+                println!("✓ {} '{}': {}", args[0], args[1], pattern_text(&track.pattern));
+                session.checkpoint(seq);
+                seq.upsert_track(track);
+            }
+            _ if input.starts_with("combine ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                if args.len() != 3 {
+                    println!("✗ Usage: combine <a> <b> <dst>");
+                    continue;
+                }
+                let (a, b) = seq.read(|s| (
+                    s.tracks.iter().find(|t| t.name == args[0]).cloned(),
+                    s.tracks.iter().find(|t| t.name == args[1]).cloned(),
+                ));
+                match (a, b) {
+                    (Some(a), Some(b)) => {
+                        let mut track = a.clone();
+                        track.name = args[2].to_string();
+                        track.pattern = combine_patterns(&a.pattern, &b.pattern);
+                        track.chords.clear();
+                        println!("✓ Combined '{}' + '{}' into '{}': {}",
+                            args[0], args[1], args[2], pattern_text(&track.pattern));
+                        session.checkpoint(seq);
+                        seq.upsert_track(track);
+                    }
+                    _ => println!("✗ Both tracks must exist"),
+                }
+            }
+            _ if input.starts_with("progression ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                if args.len() < 2 {
+                    println!("✗ Usage: progression <name> I V vi IV");
+                    continue;
+                }
+                let Some((pattern, chords)) = progression_pattern(&args[1..], seq.read(Sequencer::steps_per_bar)) else {
+                    println!("✗ Chords must be roman numerals I..VII");
+                    continue;
+                };
+                let mut track = seq.read(|s| s.tracks.iter().find(|t| t.name == args[0]).cloned())
+                    .unwrap_or_else(|| {
+                        let mut t = session.template.clone();
+                        t.name = args[0].to_string();
+                        t
+                    });
+                track.pattern = pattern;
+                track.chords = chords;
+                session.checkpoint(seq);
+                seq.upsert_track(track);
+                println!("✓ '{}' plays {} ({} bars)", args[0], args[1..].join(" "), args.len() - 1);
+            }
+            _ if input.starts_with("harmonize ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                let interval = args.get(1).and_then(|x| x.parse::<i32>().ok());
+                let (Some(name), Some(interval)) = (args.first(), interval) else {
+                    println!("✗ Usage: harmonize <name> <interval>  (e.g. harmonize lead 3)");
+                    continue;
+                };
+                if let Some(src) = seq.read(|s| s.tracks.iter().find(|t| t.name == *name).cloned()) {
+                    let dst = format!("{}_h{}", name, interval);
+                    let harmony = harmonize_track(&src, interval, &dst);
+                    println!("✓ Added harmony '{}'", dst);
+                    session.checkpoint(seq);
+                    seq.upsert_track(harmony);
+                } else {
+                    println!("✗ Track '{}' not found", name);
+                }
+            }
+            _ if input.starts_with("random ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                let steps = args.get(1).map(|n| n.parse::<usize>());
+                if args.is_empty() || args.len() > 2 || matches!(steps, Some(Err(_))) {
+                    println!("✗ Usage: random <name> [steps]");
+                    continue;
+                }
+                let track = seq.generate(|s| match steps {
+                    Some(Ok(n)) => random_track_with_len(args[0], s.scale.len(), n, &mut s.rng),
+                    _ => random_track(args[0], s.scale.len(), &mut s.rng),
+                });
+                println!("✓ Generated '{}': {} steps, {:?}, octave {}",
+                    track.name, track.pattern.len(), track.waveform, track.octave);
+                session.checkpoint(seq);
+                seq.upsert_track(track);
+            }
+            _ if input.starts_with("bassfrom ") => {
+                let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                if args.len() != 2 {
+                    println!("✗ Usage: bassfrom <chordtrack> <dst>");
+                    continue;
+                }
+                if let Some(src) = seq.read(|s| s.tracks.iter().find(|t| t.name == args[0]).cloned()) {
+                    let bass = bass_from_track(&src, args[1]);
+                    println!("✓ Bass '{}' follows '{}' at octave {}", args[1], args[0], bass.octave);
+                    session.checkpoint(seq);
+                    seq.upsert_track(bass);
+                } else {
+                    println!("✗ Track '{}' not found", args[0]);
+                }
+            }
+            _ if ["mute ", "unmute ", "solo ", "unsolo "].iter().any(|c| input.starts_with(c)) => {
+                let (cmd, name) = input.split_once(' ').unwrap();
+                let name = name.trim();
+                let (target, flag) = (name.to_string(), cmd.to_string());
+                let found = seq.edit(move |s| {
+                    let Some(track) = s.tracks.iter_mut().find(|t| t.name == target) else { return false };
+                    match flag.as_str() {
+                        "mute" => track.muted = true,
+                        "unmute" => track.muted = false,
+                        "solo" => track.soloed = true,
+                        _ => track.soloed = false,
+                    }
+                    true
+                });
+                if found {
+                    println!("✓ {} '{}'", cmd, name);
+                } else {
+                    println!("✗ Track '{}' not found", name);
                 }
             }
             _ if input.starts_with("delete ") => {
                 let name = input.strip_prefix("delete ").unwrap().trim();
-                if let Ok(mut s) = seq.lock() {
-                    if let Some(pos) = s.tracks.iter().position(|t| t.name == name) {
-                        s.tracks.remove(pos);
-                        s.voices.remove(pos);
-                        println!("✓ Deleted track '{}'", name);
-                    } else {
-                        println!("✗ Track '{}' not found", name);
-                    }
+                if let Some(pos) = seq.read(|s| s.tracks.iter().position(|t| t.name == name)) {
+                    session.checkpoint(seq);
+                    seq.remove_track(pos);
+                    println!("✓ Deleted track '{}'", name);
+                } else {
+                    println!("✗ Track '{}' not found", name);
                 }
             }
             _ => {
                 // Parse track line
                 let parts: Vec<&str> = input.splitn(2, ' ').collect();
                 if parts.len() < 2 {
-                    println!("✗ Format: <name> n\"pattern\" .o(octave) .s(\"wave\")");
+                    println!("✗ {}Format: <name> n\"pattern\" .o(octave) .s(\"wave\")", at);
                     continue;
                 }
                 
                 let name = parts[0];
-                let rest = parts[1];
+                let rest = match expand_macros(parts[1], &session.macros) {
+                    Ok(rest) => rest,
+                    Err(e) => {
+                        println!("✗ {}{}", at, e);
+                        continue;
+                    }
+                };
                 
-                if let Some(mut track) = parse_track_line(rest) {
-                    track.name = name.to_string();
-                    
-                    if let Ok(mut s) = seq.lock() {
-                        // Check if track with same name exists
-                        if let Some(existing) = s.tracks.iter_mut().find(|t| t.name == name) {
-                            *existing = track.clone();
+                match parse_track_line(&rest, &session.template) {
+                    Ok(mut track) => {
+                        track.name = name.to_string();
+
+                        session.checkpoint(seq);
+                        if seq.upsert_track(track) {
                             println!("✓ Updated track '{}'", name);
                         } else {
-                            s.add_track(track);
                             println!("✓ Added track '{}' (playing now!)", name);
                         }
                     }
-                } else {
-                    println!("✗ Failed to parse track");
+                    Err(e) => println!("✗ {}{}", at, e),
                 }
             }
         }
@@ -547,8 +2379,130 @@ fn repl_mode(seq: &Arc<Mutex<Sequencer>>) {
 // =========================
 //
 
+/// Ask which output device to use when there's more than one.
+fn choose_output_device(theme: &ColorfulTheme) -> Option<String> {
+    let devices = list_output_devices();
+    if devices.len() < 2 { return None; }
+    let default = cpal::default_host().default_output_device().and_then(|d| d.name().ok());
+    let idx = Select::with_theme(theme)
+        .with_prompt("Output device")
+        .default(devices.iter().position(|d| Some(d) == default.as_ref()).unwrap_or(0))
+        .items(&devices)
+        .interact()
+        .ok()?;
+    devices.get(idx).cloned()
+}
+
+// bars `--render` writes when `--bars` isn't given
+const DEFAULT_RENDER_BARS: usize = 4;
+
+/// Value following `flag` on the command line, e.g. `--play song.json`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+/// `--load <project.json>` with `--render <out.wav>` (and `--bars <n>`,
+/// default 4, and `--tail <secs>`) and/or `--export-midi <out.mid>`:
+/// write the files and return without any prompts.
+fn run_batch(project: ProjectData, wav: Option<&str>, bars: usize, tail_secs: f32, midi: Option<&str>) -> Result<(), String> {
+    if let Some(path) = wav {
+        let mut seq = Sequencer::from_project(project.clone(), DEFAULT_SAMPLE_RATE);
+        render_bars_to_wav(&mut seq, bars, tail_secs, path).map_err(|e| format!("{path}: {e}"))?;
+        println!("✓ Rendered {} bars to {}", bars, path);
+    }
+    if let Some(path) = midi {
+        export_midi(&project, path).map_err(|e| format!("{path}: {e}"))?;
+        println!("✓ Exported {} tracks to {}", project.tracks.len(), path);
+    }
+    Ok(())
+}
+
+/// `--play <project.json>`: no TUI, just play until Ctrl-C.
+fn play_file(path: &str, channels: Option<u16>, device: Option<String>) {
+    let sample_rate = device_sample_rate(device.as_deref()).unwrap_or(DEFAULT_SAMPLE_RATE);
+    match load_engine(path, sample_rate) {
+        Ok(engine) => {
+            println!("🎶 Playing {} (Ctrl-C to stop)", path);
+            if let Err(e) = play_audio(engine, channels, device) {
+                eprintln!("✗ Audio: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("✗ Failed to load {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The audio-thread engine for a project file, ready for any output.
+fn load_engine(path: &str, sample_rate: f32) -> Result<AudioEngine, String> {
+    let project = load_project_file(path)?;
+    let (_seq, engine) = SeqHandle::new(Sequencer::from_project(project, sample_rate));
+    Ok(engine)
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let channels = flag_value(&args, "--channels").and_then(|n| n.parse::<u16>().ok());
+    let device = flag_value(&args, "--device").map(str::to_string);
+    if let Some(pos) = args.iter().position(|a| a == "--diff") {
+        match (args.get(pos + 1), args.get(pos + 2)) {
+            (Some(a), Some(b)) => print_project_diff(a, b),
+            _ => eprintln!("✗ Usage: vibez --diff <fileA> <fileB>"),
+        }
+        return;
+    }
+    if let Some(path) = flag_value(&args, "--play") {
+        play_file(path, channels, device);
+        return;
+    }
+    let preloaded = flag_value(&args, "--load").map(|path| match load_project_file(path) {
+        Ok(project) => {
+            println!("✓ Loaded from {}", path);
+            project
+        }
+        Err(e) => {
+            eprintln!("✗ Failed to load {}", e);
+            std::process::exit(1);
+        }
+    });
+    let wav_out = flag_value(&args, "--render");
+    let midi_out = flag_value(&args, "--export-midi");
+    if wav_out.is_some() || midi_out.is_some() {
+        let Some(project) = preloaded else {
+            eprintln!("✗ Usage: vibez --load <project.json> [--render <out.wav> [--bars <n>] [--tail <secs>]] [--export-midi <out.mid>]");
+            std::process::exit(1);
+        };
+        let bars = match flag_value(&args, "--bars").map(str::parse::<usize>) {
+            None => DEFAULT_RENDER_BARS,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => {
+                eprintln!("✗ --bars needs a positive whole number");
+                std::process::exit(1);
+            }
+        };
+        let tail = flag_value(&args, "--tail").and_then(|t| t.parse::<f32>().ok()).unwrap_or(0.0).max(0.0);
+        if let Err(e) = run_batch(project, wav_out, bars, tail, midi_out) {
+            eprintln!("✗ Export failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let theme = ColorfulTheme::default();
+    let mut session = Session::default();
+    if let Some(path) = flag_value(&args, "--template") {
+        match load_template_file(path) {
+            Ok(template) => {
+                session.template = template;
+                println!("✓ Using track template from {}", path);
+            }
+            Err(e) => println!("✗ Failed to load template {}", e),
+        }
+    }
     
     println!("╔═══════════════════════════════╗");
     println!("║   V I B E Z  T R A N C E      ║");
@@ -561,29 +2515,50 @@ fn main() {
         "Start with example",
     ];
     
-    let choice = Select::with_theme(&theme)
-        .with_prompt("What would you like to do?")
-        .default(0)
-        .items(&options)
-        .interact()
-        .unwrap();
+    // pick the device first so the sequencer is built at its sample rate
+    let device = device.or_else(|| choose_output_device(&theme));
+    let sample_rate = device_sample_rate(device.as_deref()).unwrap_or(DEFAULT_SAMPLE_RATE);
+
+    // a project given with --load skips the question and is imported
+    let choice = if preloaded.is_some() {
+        2
+    } else {
+        Select::with_theme(&theme)
+            .with_prompt("What would you like to do?")
+            .default(0)
+            .items(&options)
+            .interact()
+            .unwrap()
+    };
     
     let seq = match choice {
         0 => {
             // REPL Mode - start with empty sequencer
-            let mut s = Sequencer::new(44100.0);
-            s.tracks.clear();
-            s.voices.clear();
-            Arc::new(Mutex::new(s))
+            let mut s = Sequencer::new(sample_rate);
+            s.clear_tracks();
+            s
         }
         1 => {
             // Create new
-            let mut s = Sequencer::new(44100.0);
-            s.tracks.clear();
-            s.voices.clear();
+            let mut s = Sequencer::new(sample_rate);
+            s.clear_tracks();
+
+            let modes: Vec<&str> = SCALE_TYPES.iter().map(|(name, _)| *name).collect();
+            let mode_idx = Select::with_theme(&theme)
+                .with_prompt("Scale")
+                .default(1)
+                .items(&modes)
+                .interact()
+                .unwrap();
+            let root: String = Input::with_theme(&theme)
+                .with_prompt("Root note")
+                .default("g".to_string())
+                .interact_text()
+                .unwrap();
+            s.set_scale(modes[mode_idx], &root);
             
             loop {
-                if let Some(track) = create_track_interactive(&theme) {
+                if let Some(track) = create_track_interactive(&theme, &session.template) {
                     s.add_track(track);
                 }
                 
@@ -596,63 +2571,67 @@ fn main() {
                     break;
                 }
             }
-            Arc::new(Mutex::new(s))
+            s
         }
         2 => {
             // Import
-            if let Some(project) = load_project(&theme) {
-                Arc::new(Mutex::new(Sequencer::from_project(project, 44100.0)))
+            if let Some(project) = preloaded.or_else(|| load_project(&theme)) {
+                Sequencer::from_project(project, sample_rate)
             } else {
                 println!("Failed to load. Using default.");
-                Arc::new(Mutex::new(Sequencer::new(44100.0)))
+                Sequencer::new(sample_rate)
             }
         }
         3 => {
             // Example
-            let mut s = Sequencer::new(44100.0);
-            s.tracks.clear();
-            s.voices.clear();
+            let mut s = Sequencer::new(sample_rate);
+            s.clear_tracks();
             
             let mut bass = Track::new("Bass");
-            bass.pattern = vec![0, 0, -1, 0, 3, 3, -1, 3];
+            bass.pattern = vec![0, 0, REST, 0, 3, 3, REST, 3];
             bass.octave = 2;
             bass.waveform = Waveform::Sine;
             s.add_track(bass);
             
             let mut lead = Track::new("Lead");
-            lead.pattern = vec![0, 3, 5, 7, 5, 3, 0, -1];
+            lead.pattern = vec![0, 3, 5, 7, 5, 3, 0, REST];
             lead.octave = 4;
             lead.waveform = Waveform::Saw;
             s.add_track(lead);
             
             println!("✓ Loaded example with bass + lead");
-            Arc::new(Mutex::new(s))
+            s
         }
-        _ => Arc::new(Mutex::new(Sequencer::new(44100.0))),
+        _ => Sequencer::new(sample_rate),
     };
     
     // Display tracks
-    if let Ok(s) = seq.lock() {
-        if !s.tracks.is_empty() {
-            println!("\n=== Loaded Tracks ===");
-            for track in &s.tracks {
-                println!("  • {} (O:{} T:{} W:{:?})", 
-                    track.name, track.octave, track.transpose, track.waveform);
-            }
+    if !seq.tracks.is_empty() {
+        println!("\n=== Loaded Tracks ===");
+        for track in &seq.tracks {
+            println!("  • {} (O:{} T:{} W:{:?})", 
+                track.name, track.octave, track.transpose, track.waveform);
         }
     }
     
-    // Start audio
-    let seq_audio = seq.clone();
-    std::thread::spawn(move || { play_audio(seq_audio); });
-    
-    // Give audio thread time to start
-    std::thread::sleep(Duration::from_millis(100));
-    println!("🎶 Audio running...\n");
+    // Start audio; the audio thread owns its sequencer, we keep a handle
+    let (seq, engine) = SeqHandle::new(seq);
+    // keep the stream alive for the whole session; without one we carry on
+    // silently so editing, saving and exporting still work
+    let _stream = match start_audio(engine, channels, device) {
+        Ok(stream) => {
+            println!("🎶 Audio running...\n");
+            Some(stream)
+        }
+        Err(e) => {
+            println!("⚠ No audio ({}), running silent\n", e);
+            None
+        }
+    };
     
     // If user chose REPL mode, go straight into it
     if choice == 0 {
-        repl_mode(&seq);
+        repl_mode(&seq, &mut session);
     }
     
     // Menu loop
@@ -662,6 +2641,9 @@ fn main() {
             "REPL Mode (build as you go)",
             "Add track (interactive)",
             "Save project",
+            "Export WAV",
+            "Export MIDI",
+            "Jam (play from the keyboard)",
             "Quit",
         ];
         
@@ -674,20 +2656,28 @@ fn main() {
         
         match menu_choice {
             0 => {
-                repl_mode(&seq);
+                repl_mode(&seq, &mut session);
             }
             1 => {
-                if let Some(track) = create_track_interactive(&theme) {
-                    if let Ok(mut s) = seq.lock() {
-                        s.add_track(track);
-                        println!("✓ Track added (playing now!)");
-                    }
+                if let Some(track) = create_track_interactive(&theme, &session.template) {
+                    session.checkpoint(&seq);
+                    seq.add_track(track);
+                    println!("✓ Track added (playing now!)");
                 }
             }
             2 => {
                 save_project(&seq, &theme);
             }
             3 => {
+                export_wav(&seq, &theme);
+            }
+            4 => {
+                export_midi_file(&seq, &theme);
+            }
+            5 => {
+                jam_mode(&seq, &theme);
+            }
+            6 => {
                 println!("Goodbye! 🎵");
                 break;
             }
@@ -695,3 +2685,403 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temp dir, unique to this test process.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("vibez-{}-{}", std::process::id(), name))
+            .to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn play_path_loads_a_project_and_runs_the_engine() {
+        let mut seq = Sequencer::new(DEFAULT_SAMPLE_RATE);
+        seq.tracks[0].pattern = vec![0, 2, 4, 2];
+        let path = temp_path("play.json");
+        fs::write(&path, serde_json::to_string(&seq.to_project()).unwrap()).unwrap();
+
+        let mut engine = load_engine(&path, DEFAULT_SAMPLE_RATE).unwrap();
+        fs::remove_file(&path).unwrap();
+        // null sink: render buffers the way a stream callback would and drop them
+        let mut sink = vec![0.0f32; 512 * 2];
+        let mut loudest = 0.0f32;
+        // half a second, a couple of steps at the default tempo
+        for _ in 0..(DEFAULT_SAMPLE_RATE as usize / 2 / 512) {
+            engine.fill(&mut sink, 2, |x| x);
+            loudest = sink.iter().fold(loudest, |m, x| m.max(x.abs()));
+        }
+        assert!(loudest > 0.0);
+        assert!(engine.stats.active_voices() > 0);
+        assert!(engine.stats.peak() > 0.0);
+
+        assert!(load_engine(&temp_path("missing.json"), DEFAULT_SAMPLE_RATE).is_err());
+    }
+
+    #[test]
+    fn browse_lists_only_project_files() {
+        let dir = temp_path("browse");
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["b.json", "A.JSON", "notes.txt", "old.json.bak", "json"] {
+            fs::write(std::path::Path::new(&dir).join(name), "{}").unwrap();
+        }
+        let listed = list_projects(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(listed.unwrap(), vec!["A.JSON", "b.json"]);
+        assert!(list_projects(&temp_path("no-such-dir")).is_err());
+    }
+
+    #[test]
+    fn frames_fill_every_channel_layout() {
+        // frame n is (n, -n / 2), so each slot shows which frame wrote it
+        let frames = || {
+            let mut n = 0.0;
+            move || { n += 1.0; (n, -n / 2.0) }
+        };
+        let mid = |n: f32| downmix(n, -n / 2.0);
+
+        let mut stereo = [0.0; 4];
+        fill_frames(&mut stereo, 2, frames(), |x| x);
+        assert_eq!(stereo, [1.0, -0.5, 2.0, -1.0]);
+
+        let mut mono = [0.0; 2];
+        fill_frames(&mut mono, 1, frames(), |x| x);
+        assert_eq!(mono, [mid(1.0), mid(2.0)]);
+
+        // surround: L and R first, the mid in every extra channel
+        let mut quad = [0.0; 8];
+        fill_frames(&mut quad, 4, frames(), |x| x);
+        assert_eq!(quad, [1.0, -0.5, mid(1.0), mid(1.0), 2.0, -1.0, mid(2.0), mid(2.0)]);
+
+        // the converter sees every sample, e.g. for i16 output
+        let mut ints = [0i16; 2];
+        fill_frames(&mut ints, 2, || (0.5, -1.0), |x| (x * i16::MAX as f32) as i16);
+        assert_eq!(ints, [i16::MAX / 2, -i16::MAX]);
+    }
+
+    /// (track, absolute tick, note, on?)
+    type NoteEvent = (usize, u32, u8, bool);
+
+    /// Track count, division and note events of an SMF, in file order,
+    /// checking the chunk framing on the way.
+    fn parse_smf(data: &[u8]) -> (u16, u16, Vec<NoteEvent>) {
+        let be16 = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+        let be32 = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        assert_eq!(&data[..4], b"MThd");
+        assert_eq!(be32(4), 6);
+        assert_eq!(be16(8), 1, "type 1");
+        let (tracks, division) = (be16(10), be16(12));
+
+        let mut notes = Vec::new();
+        let mut pos = 14;
+        for track in 0..tracks as usize {
+            assert_eq!(&data[pos..pos + 4], b"MTrk");
+            let end = pos + 8 + be32(pos + 4);
+            pos += 8;
+            let mut tick = 0u32;
+            let vlq = |pos: &mut usize| {
+                let mut v = 0u32;
+                loop {
+                    let b = data[*pos];
+                    *pos += 1;
+                    v = (v << 7) | (b & 0x7F) as u32;
+                    if b & 0x80 == 0 { return v; }
+                }
+            };
+            loop {
+                tick += vlq(&mut pos);
+                let status = data[pos];
+                pos += 1;
+                if status == 0xFF {
+                    let kind = data[pos];
+                    pos += 1;
+                    let len = vlq(&mut pos) as usize;
+                    pos += len;
+                    if kind == 0x2F { break; }
+                } else {
+                    notes.push((track, tick, data[pos], status & 0xF0 == 0x90));
+                    pos += 2;
+                }
+            }
+            assert_eq!(pos, end, "chunk length matches its events");
+        }
+        assert_eq!(pos, data.len());
+        (tracks, division, notes)
+    }
+
+    #[test]
+    fn midi_export_pairs_notes_on_the_step_grid() {
+        for steps_per_beat in [4, 3] {
+            let mut project = Sequencer::new(DEFAULT_SAMPLE_RATE).to_project();
+            project.steps_per_beat = steps_per_beat;
+            project.tracks[0].pattern = vec![0, TIE, REST, 2];
+            let path = temp_path("export.mid");
+            export_midi(&project, &path).unwrap();
+            let data = fs::read(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            let (tracks, division, events) = parse_smf(&data);
+            assert_eq!((tracks, division), (2, MIDI_PPQ));
+            let step = (MIDI_PPQ / steps_per_beat as u16) as u32;
+            let voices = project.tracks[0].voice_count();
+            // every note on is closed by an off for the same note
+            let mut held: Vec<(u8, u32)> = Vec::new();
+            let mut spans = Vec::new();
+            for &(track, tick, note, on) in &events {
+                assert_eq!(track, 1);
+                if on {
+                    assert!(!held.iter().any(|&(n, _)| n == note));
+                    held.push((note, tick));
+                } else {
+                    let i = held.iter().position(|&(n, _)| n == note).expect("off without on");
+                    spans.push((held.remove(i).1, tick));
+                }
+            }
+            assert!(held.is_empty());
+            // the tie carries the first note over two steps; after the
+            // rest the last note fills the final step
+            let mut expected = vec![(0, 2 * step); voices];
+            expected.extend(vec![(3 * step, 4 * step); voices]);
+            assert_eq!(spans, expected);
+        }
+    }
+
+    fn names_of(parts: &[TrackParts]) -> Vec<&str> {
+        parts.iter().map(|p| p.track.name.as_str()).collect()
+    }
+
+    #[test]
+    fn the_engine_hands_swapped_out_state_back() {
+        let names = |s: &Sequencer| s.tracks.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+        let (seq, mut engine) = SeqHandle::new(Sequencer::new(DEFAULT_SAMPLE_RATE));
+        assert!(engine.seq.tracks.capacity() >= TRACK_HEADROOM);
+        let first = seq.read(|s| s.tracks[0].name.clone());
+        let mut buf = vec![0.0f32; 64];
+
+        seq.upsert_track(Track::new(&first));
+        seq.add_track(Track::new("b"));
+        seq.remove_track(0);
+        seq.restore_tracks(vec![Track::new("c"), Track::new("d")]);
+        engine.fill(&mut buf, 2, |x| x);
+        assert_eq!(names(&engine.seq), vec!["c", "d"]);
+        assert_eq!(seq.read(names), names(&engine.seq));
+        // the replaced track, the removed one, then the old list
+        let garbage: Vec<Garbage> = seq.trash.try_iter().collect();
+        assert!(matches!(&garbage[..], [Garbage::Track(_), Garbage::Track(_), Garbage::Tracks(old)]
+            if names_of(old) == ["b"]));
+
+        let mut project = seq.read(Sequencer::to_project);
+        project.tracks = vec![Track::new("e")];
+        project.sections.clear();
+        seq.replace_project(project);
+        engine.fill(&mut buf, 2, |x| x);
+        assert_eq!(names(&engine.seq), vec!["e"]);
+        assert!(matches!(&seq.trash.try_iter().collect::<Vec<_>>()[..], [Garbage::Project(_, old)]
+            if names_of(old) == ["c", "d"]));
+    }
+
+    #[test]
+    fn song_and_key_edits_hand_the_old_values_back() {
+        let (seq, mut engine) = SeqHandle::new(Sequencer::new(DEFAULT_SAMPLE_RATE));
+        let mut buf = vec![0.0f32; 64];
+        let old_scale = seq.read(|s| s.scale.clone());
+
+        seq.save_section("intro");
+        seq.set_arrangement(Arrangement { sections: vec![("intro".to_string(), 2)] });
+        let scale = seq.change_scale(|s| s.set_root("d"));
+        seq.audition_scale();
+        let target = "nobody".to_string();
+        seq.edit(move |s| s.tracks.iter().any(|t| t.name == target));
+        engine.fill(&mut buf, 2, |x| x);
+
+        assert_eq!(engine.seq.sections, seq.read(|s| s.sections.clone()));
+        assert_eq!(engine.seq.sections[0].name, "intro");
+        assert_eq!(engine.seq.arrangement.total_bars(), 2);
+        assert_eq!(engine.seq.scale, scale);
+        assert_eq!(engine.seq.scale_name, Some(("minor".to_string(), "d".to_string())));
+        // in the order sent: the empty section list, the old arrangement,
+        // the G minor scale, the silent audition and the edit's closure
+        let garbage: Vec<Garbage> = seq.trash.try_iter().collect();
+        assert!(matches!(&garbage[..], [
+            Garbage::Sections(none),
+            Garbage::Arrangement(off),
+            Garbage::Scale(old, Some((_, root))),
+            Garbage::Notes(silent),
+            Garbage::Edit(_),
+        ] if none.is_empty() && off.total_bars() == 0 && *old == old_scale && root == "g" && silent.is_empty()));
+    }
+
+    fn device_default(channels: u16, format: cpal::SampleFormat) -> cpal::SupportedStreamConfig {
+        cpal::SupportedStreamConfig::new(channels, cpal::SampleRate(48000), cpal::SupportedBufferSize::Unknown, format)
+    }
+
+    #[test]
+    fn stream_config_clamps_channels_and_refuses_odd_formats() {
+        let stereo = device_default(2, cpal::SampleFormat::F32);
+        let pick = |channels, max| stream_config(&stereo, channels, max).unwrap();
+        assert_eq!(pick(None, None).channels, 2);
+        assert_eq!(pick(None, None).sample_rate.0, 48000);
+        assert_eq!(pick(Some(6), Some(8)).channels, 6);
+        assert_eq!(pick(Some(6), Some(4)).channels, 4);
+        assert_eq!(pick(Some(6), None).channels, 2, "unknown max keeps the default");
+        assert_eq!(pick(Some(0), Some(8)).channels, 1);
+        assert!(stream_config(&device_default(2, cpal::SampleFormat::I16), None, None).is_ok());
+        assert!(stream_config(&device_default(2, cpal::SampleFormat::U16), None, None).is_ok());
+
+        let err = stream_config(&device_default(2, cpal::SampleFormat::I32), None, None).unwrap_err();
+        assert!(matches!(err, AudioError::UnsupportedFormat(cpal::SampleFormat::I32)));
+        assert_eq!(err.to_string(), "unsupported sample format i32");
+    }
+
+    #[test]
+    fn audio_errors_read_as_sentences() {
+        assert_eq!(AudioError::NoDevice.to_string(), "no output device available");
+        let err = AudioError::Config(cpal::DefaultStreamConfigError::DeviceNotAvailable);
+        assert!(err.to_string().starts_with("couldn't read the device config: "), "{err}");
+        let err = AudioError::BuildStream(cpal::BuildStreamError::StreamConfigNotSupported);
+        assert!(err.to_string().starts_with("couldn't build the output stream: "), "{err}");
+        let err = AudioError::PlayStream(cpal::PlayStreamError::DeviceNotAvailable);
+        assert!(err.to_string().starts_with("couldn't start the output stream: "), "{err}");
+        let boxed: Box<dyn std::error::Error> = Box::new(AudioError::NoDevice);
+        assert_eq!(boxed.to_string(), "no output device available");
+    }
+
+    #[test]
+    fn projects_round_trip_through_their_format() {
+        let mut seq = Sequencer::new(DEFAULT_SAMPLE_RATE);
+        seq.tracks[0].pattern = vec![0, REST, 4, TIE];
+        seq.set_bpm(128.0);
+        let project = seq.to_project();
+        let text = |p: &ProjectData| serde_json::to_string(p).unwrap();
+
+        for name in ["song.json", "song.JSON", "song.vbz"] {
+            let path = temp_path(name);
+            serialize_project(&project, &path).unwrap();
+            let loaded = deserialize_project(&path).unwrap();
+            let _ = fs::remove_file(&path);
+            assert_eq!(text(&loaded), text(&project), "{name}");
+        }
+
+        for name in ["song.ron", "song.toml"] {
+            let path = temp_path(name);
+            let err = serialize_project(&project, &path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            assert!(err.to_string().contains("unsupported format"), "{err}");
+            assert!(!std::path::Path::new(&path).exists(), "nothing is written");
+            assert_eq!(deserialize_project(&path).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn psync_and_retrig_set_the_phase_reset() {
+        let mut free = Track::new("t");
+        free.retrig_phase = false;
+        let retrig = |line: &str| parse_track_line(line, &free).unwrap().retrig_phase;
+        assert!(!retrig("saw n\"0 3\""), "the template's setting stands");
+        assert!(retrig("saw n\"0 3\" .psync()"));
+        assert!(retrig("saw n\"0 3\" .retrig(true)"));
+        assert!(retrig("saw n\"0 3\" .retrig(on)"));
+        assert!(!retrig("saw n\"0 3\" .retrig(false)"));
+        assert!(!parse_track_line("saw n\"0 3\" .retrig(false)", &Track::new("t")).unwrap().retrig_phase);
+        assert!(parse_track_line("saw n\"0 3\" .retrig(maybe)", &free).is_err());
+    }
+
+    #[test]
+    fn diff_reports_each_changed_field() {
+        let mut seq = Sequencer::new(DEFAULT_SAMPLE_RATE);
+        seq.tracks[0].name = "bass".to_string();
+        seq.add_track(Track::new("lead"));
+        seq.add_track(Track::new("pad"));
+        let a = seq.to_project();
+        assert!(diff_projects(&a, &a).is_empty());
+
+        let mut b = a.clone();
+        b.bpm = 140.0;
+        b.mode = Some("dorian".to_string());
+        b.root = Some("d".to_string());
+        b.scale = scale_from_name("dorian", "d").unwrap();
+        b.tracks.retain(|t| t.name != "pad");
+        b.tracks.push(Track::new("hats"));
+        b.tracks[0].pattern = vec![0, REST, 4];
+        b.tracks[1].gain = 0.5;
+
+        b.time_sig_num = 7;
+        b.time_sig_den = 8;
+
+        let lines = diff_projects(&a, &b);
+        let has = |want: &str| lines.iter().any(|l| l == want);
+        assert!(has("bpm: 60 -> 140"), "{lines:#?}");
+        assert!(has("time signature: 4/4 -> 7/8"), "{lines:#?}");
+        assert!(has("scale: [7, 9, 10, 12, 14, 15, 17] -> [2, 4, 5, 7, 9, 11, 12]"), "{lines:#?}");
+        assert!(has("key: Some(\"g\") Some(\"minor\") -> Some(\"d\") Some(\"dorian\")"), "{lines:#?}");
+        assert!(has("- track 'pad' removed"), "{lines:#?}");
+        assert!(has("+ track 'hats' added"), "{lines:#?}");
+        assert!(has("~ track 'bass' pattern: \"0\" -> \"0 . 4\""), "{lines:#?}");
+        assert!(has("~ track 'lead' gain: 1.0 -> 0.5"), "{lines:#?}");
+        assert_eq!(lines.len(), 8, "nothing else differs: {lines:#?}");
+    }
+
+    #[test]
+    fn templates_set_new_track_defaults_that_lines_still_override() {
+        let template = parse_track_line(".o(2) .s(\"sine\") .trans(3)", &Track::new("Untitled")).unwrap();
+        let plain = parse_track_line("n\"0 3\"", &template).unwrap();
+        assert_eq!((plain.octave, plain.waveform, plain.transpose), (2, Waveform::Sine, 3));
+        assert_eq!(plain.pattern, vec![0, 3]);
+
+        let own = parse_track_line("n\"0 3\" .o(5) .s(\"square\")", &template).unwrap();
+        assert_eq!((own.octave, own.waveform, own.transpose), (5, Waveform::Square, 3));
+
+        // the same from a saved template file
+        let path = temp_path("template.json");
+        fs::write(&path, serde_json::to_string(&template).unwrap()).unwrap();
+        let loaded = load_template_file(&path);
+        fs::remove_file(&path).unwrap();
+        let from_file = parse_track_line("n\"0\" .o(4)", &loaded.unwrap()).unwrap();
+        assert_eq!((from_file.octave, from_file.waveform, from_file.transpose), (4, Waveform::Sine, 3));
+        assert_eq!(Track::new("t").octave, 3, "the built-in default is untouched");
+    }
+
+    #[test]
+    fn macros_expand_into_the_track_line() {
+        let mut macros = HashMap::new();
+        macros.insert("pluck".to_string(), ".s(\"saw\") .adsr(0.01,0.1,0.2,0.1) .lpf(2000)".to_string());
+        macros.insert("low".to_string(), ".o(2)".to_string());
+        macros.insert("bass".to_string(), "use pluck use low .gain(0.5)".to_string());
+
+        let line = expand_macros("n\"0 3 5\" use pluck", &macros).unwrap();
+        assert_eq!(line, "n\"0 3 5\" .s(\"saw\") .adsr(0.01,0.1,0.2,0.1) .lpf(2000)");
+        let track = parse_track_line(&line, &Track::new("lead")).unwrap();
+        assert_eq!((track.waveform, track.filter_cutoff, track.attack), (Waveform::Saw, 2000.0, 0.01));
+
+        // macros inside macros
+        let line = expand_macros("n\"0\" use bass", &macros).unwrap();
+        let track = parse_track_line(&line, &Track::new("b")).unwrap();
+        assert_eq!((track.waveform, track.octave, track.gain), (Waveform::Saw, 2, 0.5));
+
+        assert_eq!(expand_macros("n\"0\" .o(4)", &macros).unwrap(), "n\"0\" .o(4)");
+        assert!(expand_macros("n\"0\" use nothing", &macros).unwrap_err().contains("unknown macro"));
+        assert!(expand_macros("n\"0\" use", &macros).is_err());
+    }
+
+    #[test]
+    fn macro_nesting_stops_at_the_depth_limit() {
+        // m0 uses m1 uses ... down to a plain setter, MAX_MACRO_DEPTH levels deep
+        let chain = |depth: usize| {
+            let mut macros: HashMap<String, String> = (0..depth - 1)
+                .map(|i| (format!("m{i}"), format!("use m{}", i + 1)))
+                .collect();
+            macros.insert(format!("m{}", depth - 1), ".o(2)".to_string());
+            macros
+        };
+        assert_eq!(expand_macros("use m0", &chain(MAX_MACRO_DEPTH)).unwrap(), ".o(2)");
+        assert!(expand_macros("use m0", &chain(MAX_MACRO_DEPTH + 1)).is_err());
+
+        let mut cycle = HashMap::new();
+        cycle.insert("a".to_string(), "use b".to_string());
+        cycle.insert("b".to_string(), "use a".to_string());
+        let err = expand_macros("n\"0\" use a", &cycle).unwrap_err();
+        assert!(err.contains("nest too deeply"), "{err}");
+    }
+}